@@ -6,13 +6,14 @@ extern crate popsicle;
 extern crate pbr;
 
 use clap::{App, Arg};
-use pbr::{MultiBar, ProgressBar, Units};
-use std::{process, thread};
-use std::cell::RefCell;
+use pbr::{MultiBar, Units};
+use std::{mem, process, ptr, thread};
 use std::io::{self, Write};
 use std::sync::Arc;
 
-use popsicle::{DiskError, Image, Mount};
+use popsicle::{BadBlock, BlockMap, CancelToken, Device, DeviceEvent, DeviceMonitor, DiskError,
+               FlashEvent, Flashed, Image, Mount, Process, Resume, RetryOptions, SystemDisks,
+               UnmountOptions, WriteOptions};
 
 fn popsicle() -> Result<(), String> {
     let matches = App::new(env!("CARGO_PKG_NAME"))
@@ -34,18 +35,86 @@ fn popsicle() -> Result<(), String> {
                 .short("a")
                 .long("all"),
         )
+        .arg(
+            Arg::with_name("bmap")
+                .help("Block map listing the ranges of the image to write")
+                .short("b")
+                .long("bmap")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("check")
                 .help("Check written image matches read image")
                 .short("c")
                 .long("check"),
         )
+        .arg(
+            Arg::with_name("discard-zeroes")
+                .help("Zero or discard chunks of zeroes on the device, instead of writing them")
+                .short("z")
+                .long("discard-zeroes"),
+        )
+        .arg(
+            Arg::with_name("probe-capacity")
+                .help("Check that each drive can hold as much as it claims to, before writing")
+                .short("p")
+                .long("probe-capacity"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .help("Resume an interrupted flash of the image to the same drives")
+                .short("r")
+                .long("resume"),
+        )
+        .arg(
+            Arg::with_name("eject")
+                .help("Detach each drive once it has been flashed, so that it is safe to unplug")
+                .short("E")
+                .long("eject"),
+        )
+        .arg(
+            Arg::with_name("retries")
+                .help("How many times to retry a write which fails, or 0 to stop at the first error")
+                .long("retries")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("unmount")
                 .help("Unmount mounted devices")
                 .short("u")
                 .long("unmount"),
         )
+        .arg(
+            Arg::with_name("lazy-unmount")
+                .help("Detach busy filesystems when unmounting, implying --unmount")
+                .long("lazy-unmount"),
+        )
+        .arg(
+            Arg::with_name("force-unmount")
+                .help("Force unreachable filesystems to unmount, implying --unmount")
+                .long("force-unmount"),
+        )
+        .arg(
+            Arg::with_name("allow-system-disk")
+                .help("Allow flashing disks which hold the running system, or the image")
+                .long("allow-system-disk"),
+        )
+        .arg(
+            Arg::with_name("entry")
+                .help("Disk image to flash from within a zip archive")
+                .short("e")
+                .long("entry")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("wait")
+                .help(
+                    "Wait for a USB drive to be inserted if none are connected, implying --all. \
+                     Only the first drive to be inserted is flashed",
+                )
+                .short("w")
+                .long("wait"),
+        )
         .arg(
             Arg::with_name("yes")
                 .help("Continue without confirmation")
@@ -55,7 +124,12 @@ fn popsicle() -> Result<(), String> {
         .get_matches();
 
     let image_path = matches.value_of("IMAGE").expect("IMAGE not set");
-    let mut image = match Image::new(&image_path) {
+    let image = match matches.value_of("entry") {
+        Some(entry) => Image::with_entry(image_path, entry),
+        None => Image::new(image_path),
+    };
+
+    let image = match image {
         Ok(image) => image,
         Err(err) => {
             return Err(format!("error with image at '{}': {}", image_path, err));
@@ -64,10 +138,54 @@ fn popsicle() -> Result<(), String> {
 
     let image_size = image.get_size();
 
+    let bmap = match matches.value_of("bmap") {
+        Some(path) => {
+            let bmap = BlockMap::open(path)
+                .map_err(|why| format!("error with block map at '{}': {}", path, why))?;
+            if let Some(image_size) = image_size.filter(|&size| size != bmap.image_size()) {
+                return Err(format!(
+                    "block map at '{}' is for an image of {} bytes, but the image is {} bytes",
+                    path,
+                    bmap.image_size(),
+                    image_size
+                ));
+            }
+
+            Some(Arc::new(bmap))
+        }
+        None => None,
+    };
+
     let mut disk_args = vec![];
-    if matches.is_present("all") {
-        if let Err(err) = popsicle::get_disk_args(&mut disk_args) {
-            return Err(format!("error getting USB disks: {}", err));
+    if matches.is_present("all") || matches.is_present("wait") {
+        // The monitor is created first, so that no drive is missed in between.
+        let monitor = if matches.is_present("wait") {
+            let monitor = DeviceMonitor::new()
+                .map_err(|why| format!("error monitoring USB disks: {}", why))?;
+            Some(monitor)
+        } else {
+            None
+        };
+
+        let devices = Device::all().map_err(|why| format!("error getting USB disks: {}", why))?;
+        for device in devices.into_iter().filter(Device::is_portable) {
+            disk_args.push(device.path.to_string_lossy().into_owned());
+        }
+
+        // Only the first drive to be inserted is flashed, as there is no telling
+        // how many more are to follow.
+        if let (true, Some(mut monitor)) = (disk_args.is_empty(), monitor) {
+            println!("Waiting for a USB drive to be inserted; only the first is flashed...");
+            loop {
+                match monitor.next_event() {
+                    Ok(DeviceEvent::Added(ref device)) if device.is_portable() => {
+                        disk_args.push(device.path.to_string_lossy().into_owned());
+                        break;
+                    }
+                    Ok(_) => (),
+                    Err(why) => return Err(format!("error monitoring USB disks: {}", why)),
+                }
+            }
         }
     } else {
         if let Some(disks) = matches.values_of("DISKS") {
@@ -78,7 +196,7 @@ fn popsicle() -> Result<(), String> {
     }
 
     if disk_args.is_empty() {
-        return Err(format!("no disks specified"));
+        return Err("no disks specified".into());
     }
 
     let mounts = match Mount::all() {
@@ -88,34 +206,54 @@ fn popsicle() -> Result<(), String> {
         }
     };
 
+    let system = if matches.is_present("allow-system-disk") {
+        SystemDisks::default()
+    } else {
+        SystemDisks::detect(&mounts, image_path)
+            .map_err(|why| format!("error finding the disks of the running system: {}", why))?
+    };
+
+    let lazy = matches.is_present("lazy-unmount");
+    let force = matches.is_present("force-unmount");
+    let unmount = if matches.is_present("unmount") || lazy || force {
+        Some(UnmountOptions {
+            lazy,
+            force,
+            ..UnmountOptions::default()
+        })
+    } else {
+        None
+    };
+
     let disks = popsicle::disks_from_args(
         disk_args.into_iter(),
         &mounts,
-        matches.is_present("unmount"),
-    ).map_err(|why| format!("disk error: {}", why))?;
+        unmount.as_ref(),
+        image_size,
+        &system,
+    ).map_err(|why| disk_error(&why))?;
 
-    let image_data = {
-        let mut pb = ProgressBar::new(image_size);
-        pb.message("Reading image: ");
-        pb.set_units(Units::Bytes);
-        let mut data = Vec::new();
-        image
-            .read(&mut data, |total| {
-                pb.set(total);
-            })
-            .map_err(|err| format!("image error with image at '{}': {}", image_path, err))?;
-
-        pb.finish();
-        Arc::new(data)
-    };
+    for (disk_path, _, released) in &disks {
+        for swap in &released.swaps {
+            eprintln!("deactivated swap on '{}': {:?}", disk_path, swap);
+        }
+        for (source, dest) in &released.mounts {
+            eprintln!("unmounted '{}': {:?} was mounted at {:?}", disk_path, source, dest);
+        }
+    }
 
     if !matches.is_present("yes") {
         println!(
             "Are you sure you want to flash '{}' to the following drives?",
             image_path
         );
-        for ref disk_tuple in disks.iter() {
-            println!("  - {}", disk_tuple.0);
+        for (disk_path, ..) in &disks {
+            match Device::from_path(disk_path) {
+                Ok(ref device) if !device.label().is_empty() => {
+                    println!("  - {} ({})", disk_path, device.label())
+                }
+                _ => println!("  - {}", disk_path),
+            }
         }
 
         print!("y/N: ");
@@ -125,58 +263,218 @@ fn popsicle() -> Result<(), String> {
         io::stdin().read_line(&mut confirm).unwrap();
 
         if confirm.trim() != "y" && confirm.trim() != "yes" {
-            return Err(format!("exiting without flashing"));
+            return Err("exiting without flashing".into());
         }
     }
 
-    let check = matches.is_present("check");
+    let resume = if matches.is_present("resume") {
+        let resume = Resume::new(Resume::default_dir(), &image)
+            .map_err(|why| format!("error with image at '{}': {}", image_path, why))?;
+        Some(resume)
+    } else {
+        None
+    };
+
+    let mut retry = RetryOptions::default();
+    if let Some(retries) = matches.value_of("retries") {
+        retry.attempts = retries
+            .parse::<u32>()
+            .map_err(|_| format!("invalid number of retries: '{}'", retries))?;
+    }
+
+    let options = WriteOptions {
+        check: matches.is_present("check"),
+        bmap,
+        discard_zeroes: matches.is_present("discard-zeroes"),
+        eject: matches.is_present("eject"),
+        probe_capacity: matches.is_present("probe-capacity"),
+        resume,
+        retry,
+    };
+
+    let cancel = CancelToken::new();
+    cancel_on_interrupt(cancel.clone())
+        .map_err(|why| format!("unable to handle interrupts: {}", why))?;
+
+    println!();
 
-    println!("");
+    let mb = MultiBar::new();
 
-    let mut mb = MultiBar::new();
+    let (reader, streams) = image.stream(disks.len());
 
     let mut threads = Vec::new();
-    for (disk_path, mut disk) in disks {
-        let mut pb = mb.create_bar(image_size);
+    for ((disk_path, disk, _), stream) in disks.into_iter().zip(streams) {
+        // Without the size of the image, progress is shown against the size of the disk.
+        let mut pb = mb.create_bar(image_size.or_else(|| disk.size().ok()).unwrap_or(0));
         pb.message(&format!("W {}: ", disk_path));
         pb.set_units(Units::Bytes);
         pb.set(0);
 
-        let image_data = image_data.clone();
-        let pb = RefCell::new(pb);
-        threads.push(thread::spawn(move || -> Result<(), DiskError> {
+        let options = options.clone();
+        let cancel = cancel.clone();
+        threads.push(thread::spawn(move || -> Result<(String, Flashed), DiskError> {
+            // The phase of the flash is shown as a prefix to the path of its disk.
+            let label = |phase| format!("{} {}: ", phase, disk_path);
+            let mut reached = 0;
+            let progress = |event| match event {
+                FlashEvent::Started => (),
+                FlashEvent::Probing => pb.message(&label("P")),
+                FlashEvent::Writing { bytes } => {
+                    pb.message(&label("W"));
+                    reached = pb.set(bytes);
+                }
+                FlashEvent::Syncing => pb.message(&label("S")),
+                FlashEvent::Verifying { bytes } => {
+                    pb.message(&label("V"));
+                    reached = pb.set(bytes);
+                }
+                FlashEvent::Finished => pb.finish(),
+                FlashEvent::Cancelled => {
+                    // Finishing fills the bar, so only the label is left, along
+                    // with how far the flash got.
+                    pb.show_bar = false;
+                    pb.show_counter = false;
+                    pb.show_percent = false;
+                    pb.show_speed = false;
+                    pb.show_time_left = false;
+                    let mb = reached as f64 / (1024.0 * 1024.0);
+                    pb.message(&format!("{}cancelled at {:.2} MB", label("C"), mb));
+                    pb.finish();
+                }
+                FlashEvent::Failed { .. } => {
+                    pb.message(&label("!"));
+                    pb.finish();
+                }
+            };
+
             popsicle::write_to_disk(
-                |msg| pb.borrow_mut().message(msg),
-                || pb.borrow_mut().finish(),
-                |progress| {
-                    pb.borrow_mut().set(progress);
-                },
+                progress,
+                &cancel,
                 disk,
-                disk_path,
-                image_size,
-                &&image_data,
-                check,
-            )
+                disk_path.clone(),
+                stream,
+                &options,
+            ).map(|flashed| (disk_path, flashed))
         }));
     }
 
     mb.listen();
 
+    reader
+        .join()
+        .unwrap()
+        .map_err(|why| format!("image error with image at '{}': {}", image_path, why))?;
+
     for thread in threads {
-        thread
+        let (disk_path, flashed) = thread
             .join()
             .unwrap()
-            .map_err(|why| format!("disk error: {}", why))?;
+            .map_err(|why| disk_error(&why))?;
+
+        if !flashed.bad_blocks.is_empty() {
+            eprintln!(
+                "popsicle: some writes to '{}' had to be retried; the drive or its connection may be unreliable{}",
+                disk_path,
+                bad_blocks(&flashed.bad_blocks)
+            );
+        }
+
+        if options.eject && !flashed.ejected {
+            eprintln!("popsicle: '{}' could not be detached; eject it before unplugging it", disk_path);
+        }
+
+        if flashed.ejected {
+            println!("{}: safe to remove", disk_path);
+        } else if !flashed.rescanned && flashed.partitionable {
+            eprintln!(
+                "popsicle: the partition table of '{}' could not be re-read; reconnect the drive to see its partitions",
+                disk_path
+            );
+        } else if !flashed.partitions.is_empty() {
+            let partitions: Vec<_> = flashed.partitions.iter().map(|path| path.display().to_string()).collect();
+            println!("{}: found partitions {}", disk_path, partitions.join(", "));
+        }
     }
 
     Ok(())
 }
 
+/// Describes an error with a disk, along with the processes which are using
+/// the disk, if it is busy.
+fn disk_error(why: &DiskError) -> String {
+    let mut message = format!("disk error: {}", why);
+    if let DiskError::BadBlocks { ref blocks, .. } = *why {
+        message.push_str(&bad_blocks(blocks));
+    }
+
+    if let Some(Ok(processes)) = why.busy_disk().map(Process::holding) {
+        for process in processes {
+            message.push_str(&format!(
+                "\n  - {} ({}) is using {}",
+                process.command,
+                process.pid,
+                process.path.display()
+            ));
+        }
+    }
+
+    message
+}
+
+/// Cancels the flashes once an interrupt is received, such as from Ctrl-C,
+/// so that each disk is left synced. A second interrupt exits at once.
+///
+/// Interrupts are blocked here, before the flashing threads are spawned, as
+/// threads inherit the mask. Only the thread which waits for them sees them.
+fn cancel_on_interrupt(cancel: CancelToken) -> io::Result<()> {
+    let signals = unsafe {
+        let mut signals: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        match libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut()) {
+            0 => signals,
+            errno => return Err(io::Error::from_raw_os_error(errno)),
+        }
+    };
+
+    thread::spawn(move || {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&signals, &mut signal) } == 0 {
+            eprintln!("\npopsicle: cancelling; interrupt again to exit at once");
+            cancel.cancel();
+        }
+
+        if unsafe { libc::sigwait(&signals, &mut signal) } == 0 {
+            process::exit(130);
+        }
+    });
+
+    Ok(())
+}
+
+/// Lists the regions which needed retries, and whether they were written.
+fn bad_blocks(blocks: &[BadBlock]) -> String {
+    let mut report = String::new();
+    for block in blocks {
+        let outcome = if block.recovered { "written" } else { "failed" };
+        report.push_str(&format!(
+            "\n  - {}:{} {} after {} retries: {}",
+            block.offset,
+            block.offset + block.len,
+            outcome,
+            block.retries,
+            block.error
+        ));
+    }
+
+    report
+}
+
 fn main() {
     match popsicle() {
         Ok(()) => (),
         Err(err) => {
-            writeln!(io::stderr(), "popsicle: {}", err).unwrap();
+            eprintln!("popsicle: {}", err);
             process::exit(1);
         }
     }
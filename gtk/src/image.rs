@@ -8,10 +8,11 @@ use std::sync::mpsc::Receiver;
 pub fn image_load_event_loop(path_receiver: Receiver<PathBuf>, buffer: &BufferingData) {
     while let Ok(path) = path_receiver.recv() {
         buffer.state.store(0b1, Ordering::SeqCst);
-        let (ref mut name, ref mut data) = *buffer.data.lock().unwrap();
-        match load_image(&path, data) {
-            Ok(_) => {
+        let (ref mut name, ref mut image) = *buffer.data.lock().unwrap();
+        match load_image(&path) {
+            Ok(new_image) => {
                 *name = path;
+                *image = Some(new_image);
                 buffer.state.store(0b10, Ordering::SeqCst);
             }
             Err(why) => {
@@ -22,24 +23,7 @@ pub fn image_load_event_loop(path_receiver: Receiver<PathBuf>, buffer: &Bufferin
     }
 }
 
-pub fn load_image<P: AsRef<Path>>(path: P, data: &mut Vec<u8>) -> io::Result<()> {
-    let path = path.as_ref();
-    let mut new_image = match Image::new(path) {
-        Ok(image) => image,
-        Err(why) => {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("unable to open image: {}", why),
-            ));
-        }
-    };
-
-    if let Err(why) = new_image.read(data, |_| ()) {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("unable to open image: {}", why),
-        ));
-    }
-
-    Ok(())
+pub fn load_image<P: AsRef<Path>>(path: P) -> io::Result<Image> {
+    Image::new(path.as_ref())
+        .map_err(|why| io::Error::other(format!("unable to open image: {}", why)))
 }
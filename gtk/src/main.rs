@@ -7,7 +7,6 @@ extern crate pango;
 extern crate pwd;
 extern crate sha3;
 
-mod block;
 mod image;
 mod ui;
 
@@ -17,8 +16,6 @@ use std::sync::mpsc::channel;
 use std::thread;
 use ui::{App, Connect};
 
-pub use block::BlockDevice;
-
 fn main() {
     // If running in pkexec, restore home directory for open dialog
     if let Ok(pkexec_uid) = env::var("PKEXEC_UID") {
@@ -4,6 +4,7 @@ pub struct DevicesView {
     pub container:  Box,
     pub list:       ListBox,
     pub select_all: CheckButton,
+    pub eject:      CheckButton,
 }
 
 impl DevicesView {
@@ -28,6 +29,8 @@ impl DevicesView {
         let select_scroller = ScrolledWindow::new(None, None);
         select_scroller.add(&list);
 
+        let eject = CheckButton::new_with_label("Eject drives once they are flashed");
+
         let left_panel = Box::new(Orientation::Vertical, 0);
         left_panel
             .get_style_context()
@@ -41,6 +44,7 @@ impl DevicesView {
         right_panel.pack_start(&topic, false, false, 0);
         right_panel.pack_start(&description, false, false, 0);
         right_panel.pack_start(&select_scroller, true, true, 0);
+        right_panel.pack_start(&eject, false, false, 0);
 
         let container = Box::new(Orientation::Horizontal, 0);
         container.pack_start(&left_panel, false, false, 0);
@@ -50,6 +54,7 @@ impl DevicesView {
             container,
             list,
             select_all,
+            eject,
         }
     }
 }
@@ -16,6 +16,13 @@ impl OpenDialog {
         let filter = FileFilter::new();
         filter.add_pattern("*.iso");
         filter.add_pattern("*.img");
+        filter.add_pattern("*.bz2");
+        filter.add_pattern("*.gz");
+        filter.add_pattern("*.xz");
+        filter.add_pattern("*.zip");
+        filter.add_pattern("*.zst");
+        filter.add_pattern("*.qcow2");
+        filter.add_pattern("*.vhd");
 
         // Add the cancel and open buttons to that dialog.
         open_dialog.add_button("Cancel", ResponseType::Cancel.into());
@@ -2,26 +2,44 @@ use digest::{Digest, Input};
 use gtk::{self, EntryExt};
 use md5::Md5;
 use sha3::Sha3_256;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
 
-fn md5_hasher(data: &[u8]) -> String {
+/// Feeds the file at `path` to `process`, one buffer at a time.
+fn read_file<F: FnMut(&[u8])>(path: &Path, mut process: F) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(()),
+            read => process(&buffer[..read]),
+        }
+    }
+}
+
+fn md5_hasher(path: &Path) -> io::Result<String> {
     let mut hasher = Md5::default();
-    hasher.process(data);
-    format!("{:x}", hasher.result())
+    read_file(path, |data| hasher.process(data))?;
+    Ok(format!("{:x}", hasher.result()))
 }
 
-fn sha256_hasher(data: &[u8]) -> String {
+fn sha256_hasher(path: &Path) -> io::Result<String> {
     let mut hasher = Sha3_256::default();
-    hasher.process(data);
-    format!("{:x}", hasher.result())
+    read_file(path, |data| hasher.process(data))?;
+    Ok(format!("{:x}", hasher.result()))
 }
 
-pub(crate) fn set(entry: &gtk::Entry, hash: &str, data: &[u8]) {
+pub(crate) fn set(entry: &gtk::Entry, hash: &str, path: &Path) {
     let hash = match hash {
         "Type" => return,
-        "SHA256" => sha256_hasher(data),
-        "MD5" => md5_hasher(data),
+        "SHA256" => sha256_hasher(path),
+        "MD5" => md5_hasher(path),
         _ => unimplemented!(),
     };
 
-    entry.get_buffer().set_text(&hash);
+    match hash {
+        Ok(hash) => entry.get_buffer().set_text(&hash),
+        Err(why) => eprintln!("popsicle-gtk: unable to hash image: {}", why),
+    }
 }
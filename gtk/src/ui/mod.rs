@@ -16,14 +16,13 @@ use std::mem;
 use std::path::PathBuf;
 use std::process;
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::AtomicUsize;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
 use std::thread::JoinHandle;
 use std::time::Instant;
 
 use gtk;
 use gtk::*;
-use popsicle::DiskError;
+use popsicle::{CancelToken, DiskError, FlashEvent, Flashed, Released, SystemDisks};
 
 const CSS: &str = include_str!("ui.css");
 
@@ -40,9 +39,13 @@ pub struct State {
     pub bars: RefCell<Vec<(ProgressBar, Label)>>,
     /// Contains a list of devices detected, and their check buttons.
     pub devices: Mutex<Vec<(String, CheckButton)>>,
+    /// The disks which hold the running system or the image, which may not be flashed.
+    pub system_disks: RefCell<SystemDisks>,
     /// Holds the task threads that write the image to each device.
     /// The handles may contain errors when joined, for printing on the summary page.
-    pub task_handles: Mutex<Vec<JoinHandle<Result<(), DiskError>>>>,
+    pub task_handles: Mutex<Vec<JoinHandle<Result<Flashed, DiskError>>>>,
+    /// Cancels every flash task which is in progress.
+    pub cancel: RefCell<CancelToken>,
     /// Contains progress data regarding each active flash task -- namely the progress.
     pub tasks: Mutex<Vec<FlashTask>>,
     /// Stores an integer which defines the currently-active view.
@@ -51,7 +54,8 @@ pub struct State {
     pub start: RefCell<Instant>,
     pub buffer: Arc<BufferingData>,
     pub image_sender: Sender<PathBuf>,
-    pub image_length: Cell<usize>,
+    /// The size of the image, if it is known before the image is written.
+    pub image_length: Cell<Option<u64>>,
 }
 
 impl State {
@@ -59,21 +63,90 @@ impl State {
         State {
             bars: RefCell::new(Vec::new()),
             devices: Mutex::new(Vec::new()),
+            system_disks: RefCell::new(SystemDisks::default()),
             task_handles: Mutex::new(Vec::new()),
+            cancel: RefCell::new(CancelToken::new()),
             tasks: Mutex::new(Vec::new()),
             view: Cell::new(0),
             start: RefCell::new(unsafe { mem::uninitialized() }),
             buffer: Arc::new(BufferingData::new()),
             image_sender,
-            image_length: Cell::new(0),
+            image_length: Cell::new(None),
         }
     }
 }
 
 pub struct FlashTask {
-    progress: Arc<AtomicUsize>,
+    /// Receives the events of the flash from the thread which performs it.
+    events:   Receiver<FlashEvent>,
+    /// The number of bytes which the progress is measured against.
+    length:   u64,
+    /// What the flash is doing, which is shown beneath its progress bar.
+    phase:    &'static str,
+    progress: usize,
     previous: Arc<Mutex<[usize; 7]>>,
-    finished: Arc<AtomicUsize>,
+    finished: bool,
+    /// The swaps and mounts which were released from the disk before it was flashed.
+    released: Released,
+}
+
+impl FlashTask {
+    fn new(events: Receiver<FlashEvent>, length: u64, released: Released) -> FlashTask {
+        FlashTask {
+            events,
+            length,
+            phase: "Starting",
+            progress: 0,
+            previous: Arc::new(Mutex::new([0; 7])),
+            finished: false,
+            released,
+        }
+    }
+
+    /// Describes what was released from the disk, if anything was.
+    fn released(&self) -> Option<String> {
+        let swaps = self.released.swaps.iter()
+            .map(|swap| format!("deactivated swap on {}", swap.display()));
+        let mounts = self.released.mounts.iter()
+            .map(|(_, dest)| format!("unmounted {}", dest.to_string_lossy()));
+        let released = swaps.chain(mounts).collect::<Vec<_>>();
+        if released.is_empty() {
+            None
+        } else {
+            Some(released.join(", "))
+        }
+    }
+
+    /// Applies the events which were sent since the task was last updated.
+    fn update(&mut self) {
+        loop {
+            match self.events.try_recv() {
+                Ok(FlashEvent::Started) => self.phase = "Starting",
+                Ok(FlashEvent::Probing) => self.phase = "Probing capacity",
+                Ok(FlashEvent::Writing { bytes }) => {
+                    self.phase = "Writing";
+                    self.progress = bytes as usize;
+                }
+                Ok(FlashEvent::Syncing) => self.phase = "Syncing",
+                Ok(FlashEvent::Verifying { bytes }) => {
+                    self.phase = "Verifying";
+                    self.progress = bytes as usize;
+                }
+                Ok(FlashEvent::Finished) => self.phase = "Finished",
+                Ok(FlashEvent::Cancelled) => self.phase = "Cancelled",
+                Ok(FlashEvent::Failed { .. }) => self.phase = "Failed",
+                Err(TryRecvError::Empty) => return,
+                // The thread is gone once the flash has ended, one way or another.
+                Err(TryRecvError::Disconnected) => {
+                    self.finished = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Whether the progress of the task is measured in bytes.
+    fn is_transferring(&self) -> bool { self.phase == "Writing" || self.phase == "Verifying" }
 }
 
 impl App {
@@ -1,27 +1,26 @@
 use super::{hash, App, FlashTask, OpenDialog};
-use super::super::BlockDevice;
 
-use std::mem;
 use std::ops::{Deref, DerefMut};
-use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::path::PathBuf;
+use std::sync::{mpsc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
 use std::time::Instant;
 
 use gtk;
 use gtk::*;
-use popsicle::{self, DiskError};
+use popsicle::{self, CancelToken, Device, DeviceEvent, DeviceMonitor, DiskError, Flashed, Image,
+               Process, SystemDisks, UnmountOptions, WriteOptions};
 
 pub struct BufferingData {
-    pub data:  Mutex<(PathBuf, Vec<u8>)>,
+    pub data:  Mutex<(PathBuf, Option<Image>)>,
     pub state: AtomicUsize,
 }
 
 impl BufferingData {
     pub fn new() -> BufferingData {
         BufferingData {
-            data:  Mutex::new((PathBuf::new(), Vec::new())),
+            data:  Mutex::new((PathBuf::new(), None)),
             state: 0.into(),
         }
     }
@@ -56,6 +55,10 @@ pub trait Connect {
     /// Programs the action that will be performed when the check all button is clicked.
     fn connect_check_all(&self);
 
+    /// Adds and removes devices from the device selection view as they are connected and
+    /// disconnected.
+    fn connect_device_monitor(&self);
+
     /// Adds a function for GTK to execute when the application is idle, to monitor and
     /// update the progress bars for devices that are being flashed, and to generate
     /// the summary view after all devices have been flashed.
@@ -69,6 +72,7 @@ impl Connect for App {
         self.connect_back_button();
         self.connect_next_button();
         self.connect_check_all();
+        self.connect_device_monitor();
         self.watch_flashing_devices();
 
         Connected(self)
@@ -88,10 +92,10 @@ impl Connect for App {
         let hash_label = self.content.image_view.hash_label.clone();
         self.content.image_view.hash.connect_changed(move |hash| {
             if state.buffer.state.load(Ordering::SeqCst) == 0b010 {
-                let (_, ref data) = *state.buffer.data.lock().unwrap();
+                let (ref path, _) = *state.buffer.data.lock().unwrap();
                 hash_label.set_icon_from_icon_name(EntryIconPosition::Primary, "gnome-spinner");
                 hash_label.set_icon_sensitive(EntryIconPosition::Primary, true);
-                hash::set(&hash_label, hash.get_active_text().unwrap().as_str(), data);
+                hash::set(&hash_label, hash.get_active_text().unwrap().as_str(), path);
                 hash_label.set_icon_sensitive(EntryIconPosition::Primary, false);
             }
         });
@@ -117,6 +121,12 @@ impl Connect for App {
                         c.add_class("suggested-action");
                     });
                 }
+                // Cancel the flashes, which then end on the summary as usual.
+                2 => {
+                    state.cancel.borrow().cancel();
+                    back.set_sensitive(false);
+                    return;
+                }
                 _ => unreachable!(),
             }
 
@@ -126,6 +136,7 @@ impl Connect for App {
 
     fn connect_next_button(&self) {
         let back = self.header.back.clone();
+        let eject = self.content.devices_view.eject.clone();
         let list = self.content.devices_view.list.clone();
         let next = self.header.next.clone();
         let stack = self.content.container.clone();
@@ -135,7 +146,7 @@ impl Connect for App {
         next.connect_clicked(move |next| {
             let device_list = &state.devices;
             state.buffer.state.store(0b1000, Ordering::SeqCst);
-            let (_, ref mut image_data) = *state.buffer.data.lock().unwrap();
+            let (ref image_path, ref mut image) = *state.buffer.data.lock().unwrap();
             let start = &state.start;
             let task_handles = &state.task_handles;
             let bars = &state.bars;
@@ -161,45 +172,75 @@ impl Connect for App {
                         .skip(1)
                         .for_each(|widget| widget.destroy());
 
-                    let mut devices = vec![];
-                    if let Err(why) = popsicle::get_disk_args(&mut devices) {
-                        eprintln!("popsicle: unable to get devices: {}", why);
-                    }
+                    let devices = match Device::all() {
+                        Ok(devices) => devices.into_iter().filter(Device::is_portable).collect(),
+                        Err(why) => {
+                            eprintln!("popsicle: unable to get devices: {}", why);
+                            Vec::new()
+                        }
+                    };
+
+                    let system = popsicle::Mount::all()
+                        .and_then(|mounts| SystemDisks::detect(&mounts, image_path))
+                        .unwrap_or_else(|why| {
+                            eprintln!("popsicle-gtk: unable to find the system's disks: {}", why);
+                            SystemDisks::default()
+                        });
 
+                    let image_size = image.as_ref().and_then(|image| image.get_size());
                     let mut device_list = device_list.lock().unwrap();
                     device_list.clear();
                     for device in &devices {
-                        let name = Path::new(&device).canonicalize().unwrap();
-                        let button = if let Some(block) = BlockDevice::new(&name) {
-                            CheckButton::new_with_label(&[
-                                &block.label(),
-                                " (",
-                                &name.to_string_lossy(),
-                                ")",
-                            ].concat())
-                        } else {
-                            CheckButton::new_with_label(&name.to_string_lossy())
-                        };
-
+                        let button = device_button(device, image_size, &system);
                         list.insert(&button, -1);
-                        device_list.push((device.clone(), button));
+                        device_list.push((device.path.to_string_lossy().into_owned(), button));
                     }
 
+                    *state.system_disks.borrow_mut() = system;
+
                     list.show_all();
                 }
                 // Begin the device flashing process
                 1 => {
                     let device_list = device_list.lock().unwrap();
-                    let devs = device_list.iter().map(|x| x.0.clone());
+                    let devs: Vec<String> = device_list
+                        .iter()
+                        .filter(|(_, button)| button.get_active() && button.get_sensitive())
+                        .map(|x| x.0.clone())
+                        .collect();
+
+                    // Without a device to flash, the flash would never finish.
+                    if devs.is_empty() {
+                        return;
+                    }
+
+                    let image_size = image.as_ref().and_then(|image| image.get_size());
                     // TODO: Handle Error
                     let mounts = popsicle::Mount::all().unwrap();
-                    // TODO: Handle Error
-                    let disks = popsicle::disks_from_args(devs, &mounts, true).unwrap();
+                    let disks = match popsicle::disks_from_args(
+                        devs.into_iter(),
+                        &mounts,
+                        Some(&UnmountOptions::default()),
+                        image_size,
+                        &state.system_disks.borrow(),
+                    ) {
+                        Ok(disks) => disks,
+                        Err(why) => {
+                            show_disk_error(&why);
+                            return;
+                        }
+                    };
 
-                    back.set_visible(false);
+                    back.set_label("Cancel");
                     next.set_visible(false);
                     stack.set_visible_child_name("flash");
 
+                    let eject = eject.get_active();
+
+                    // Every flash may be cancelled at once, or each one by itself.
+                    let cancel_all = CancelToken::new();
+                    *state.cancel.borrow_mut() = cancel_all.clone();
+
                     // Clear the progress bar summaries.
                     let mut bars = bars.borrow_mut();
                     bars.clear();
@@ -209,34 +250,26 @@ impl Connect for App {
                     let mut tasks = tasks.lock().unwrap();
                     let mut task_handles = task_handles.lock().unwrap();
 
-                    // Take ownership of the data, so that we may wrap it within an `Arc`
-                    // and redistribute it across threads.
-                    //
-                    // Note: Possible optimization could be done to avoid the wrap.
-                    //       Avoiding the wrap could eliminate two allocations.
-                    let mut data = Vec::new();
-                    mem::swap(&mut data, image_data);
-                    let image_data = Arc::new(data);
+                    // Take ownership of the image, so that it may be streamed to each device.
+                    let image = image.take().expect("image was not loaded");
+                    let (reader, streams) = image.stream(disks.len());
+                    thread::spawn(move || {
+                        if let Err(why) = reader.join().unwrap() {
+                            eprintln!("popsicle-gtk: image reading error: {}", why);
+                        }
+                    });
 
-                    for (id, (disk_path, mut disk)) in disks.into_iter().enumerate() {
+                    let disks = disks.into_iter().zip(streams);
+                    for (id, ((disk_path, disk, released), stream)) in disks.enumerate() {
                         let id = id as i32;
-                        let image_data = image_data.clone();
-                        let progress = Arc::new(AtomicUsize::new(0));
-                        let finished = Arc::new(AtomicUsize::new(0));
                         let bar = ProgressBar::new();
                         bar.set_hexpand(true);
 
-                        let label = {
-                            let disk_path = Path::new(&disk_path).canonicalize().unwrap();
-                            if let Some(block) = BlockDevice::new(&disk_path) {
-                                Label::new(
-                                    [&block.label(), " (", &disk_path.to_string_lossy(), ")"]
-                                        .concat()
-                                        .as_str(),
-                                )
-                            } else {
-                                Label::new(disk_path.to_string_lossy().as_ref())
+                        let label = match Device::from_path(&disk_path) {
+                            Ok(ref device) if !device.label().is_empty() => {
+                                Label::new([&device.label(), " (", &disk_path, ")"].concat().as_str())
                             }
+                            _ => Label::new(disk_path.as_str()),
                         };
 
                         label.set_justify(Justification::Right);
@@ -248,39 +281,47 @@ impl Connect for App {
                         let bar_container = Box::new(Orientation::Vertical, 0);
                         bar_container.pack_start(&bar, false, false, 0);
                         bar_container.pack_start(&bar_label, false, false, 0);
+                        let cancel = cancel_all.child();
+                        let stop = Button::new_with_label("Cancel");
+                        stop.set_valign(Align::Center);
+                        stop.connect_clicked({
+                            let cancel = cancel.clone();
+                            move |stop| {
+                                cancel.cancel();
+                                stop.set_sensitive(false);
+                            }
+                        });
+
                         summary_grid.attach(&label, 0, id, 1, 1);
                         summary_grid.attach(&bar_container, 1, id, 1, 1);
+                        summary_grid.attach(&stop, 2, id, 1, 1);
                         bars.push((bar, bar_label));
 
-                        // Spawn a thread that will update the progress value over time.
+                        // Spawn a thread that will send the progress of the flash over time.
                         //
-                        // The value will be stored within an intermediary atomic integer,
-                        // because it is unsafe to send GTK widgets across threads.
-                        task_handles.push({
-                            let progress = progress.clone();
-                            let finished = finished.clone();
-                            thread::spawn(move || -> Result<(), DiskError> {
-                                let result = popsicle::write_to_disk(
-                                    |_msg| (),
-                                    || (),
-                                    |value| progress.store(value as usize, Ordering::SeqCst),
-                                    disk,
-                                    disk_path,
-                                    image_data.len() as u64,
-                                    &image_data,
-                                    false,
-                                );
-
-                                finished.store(1, Ordering::SeqCst);
-                                result
-                            })
-                        });
-
-                        tasks.push(FlashTask {
-                            previous: Arc::new(Mutex::new([0; 7])),
-                            progress,
-                            finished,
-                        });
+                        // The events are sent through a channel, and applied to the progress
+                        // bars by the main thread, because it is unsafe to send GTK widgets
+                        // across threads.
+                        // Without the size of the image, progress is measured against the disk.
+                        let length = image_size.or_else(|| disk.size().ok()).unwrap_or(0);
+                        let (sender, events) = mpsc::channel();
+                        task_handles.push(thread::spawn(move || -> Result<Flashed, DiskError> {
+                            popsicle::write_to_disk(
+                                |event| {
+                                    let _ = sender.send(event);
+                                },
+                                &cancel,
+                                disk,
+                                disk_path,
+                                stream,
+                                &WriteOptions {
+                                    eject,
+                                    ..WriteOptions::default()
+                                },
+                            )
+                        }));
+
+                        tasks.push(FlashTask::new(events, length, released));
                     }
 
                     summary_grid.show_all();
@@ -303,13 +344,84 @@ impl Connect for App {
                     .lock()
                     .unwrap()
                     .iter()
+                    .filter(|(_, device)| device.get_sensitive())
                     .for_each(|&(_, ref device)| device.set_active(true));
             }
         });
     }
 
+    fn connect_device_monitor(&self) {
+        let list = self.content.devices_view.list.clone();
+        let state = self.state.clone();
+
+        // The monitor blocks while waiting for events, so it is given a thread of its own.
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let monitor = match DeviceMonitor::new() {
+                Ok(monitor) => monitor,
+                Err(why) => {
+                    eprintln!("popsicle-gtk: unable to monitor devices: {}", why);
+                    return;
+                }
+            };
+
+            for event in monitor {
+                match event {
+                    Ok(event) => if sender.send(event).is_err() {
+                        break;
+                    },
+                    Err(why) => {
+                        eprintln!("popsicle-gtk: unable to monitor devices: {}", why);
+                        break;
+                    }
+                }
+            }
+        });
+
+        gtk::timeout_add(500, move || {
+            for event in receiver.try_iter() {
+                // Devices are only listed while they are being selected.
+                if state.view.get() != 1 {
+                    continue;
+                }
+
+                let mut device_list = state.devices.lock().unwrap();
+                let (path, added) = match event {
+                    DeviceEvent::Added(ref device) if device.is_portable() => {
+                        (device.path.to_string_lossy().into_owned(), Some(device))
+                    }
+                    DeviceEvent::Added(_) => continue,
+                    DeviceEvent::Removed { ref path, .. } => (path.to_string_lossy().into_owned(), None),
+                };
+
+                // A device whose media has changed is replaced.
+                if let Some(position) = device_list.iter().position(|(name, _)| *name == path) {
+                    let (_, button) = device_list.remove(position);
+                    if let Some(row) = button.get_parent() {
+                        row.destroy();
+                    }
+                }
+
+                if let Some(device) = added {
+                    let button = device_button(
+                        device,
+                        state.image_length.get(),
+                        &state.system_disks.borrow(),
+                    );
+                    list.insert(&button, -1);
+                    list.show_all();
+                    device_list.push((path, button));
+                }
+            }
+
+            Continue(true)
+        });
+    }
+
     fn watch_flashing_devices(&self) {
         let stack = self.content.container.clone();
+        let back = self.header.back.clone();
+        let eject = self.content.devices_view.eject.clone();
         let next = self.header.next.clone();
         let description = self.content.summary_view.description.clone();
         let list = self.content.summary_view.list.clone();
@@ -336,10 +448,10 @@ impl Connect for App {
                 }
                 0b0010 => {
                     chooser_container.set_visible_child_name("chooser");
-                    let (ref path, ref data) = *state.buffer.data.lock().unwrap();
+                    let (ref path, ref image) = *state.buffer.data.lock().unwrap();
                     next.set_sensitive(true);
                     image_label.set_text(&path.file_name().unwrap().to_string_lossy());
-                    image_length.set(data.len());
+                    image_length.set(image.as_ref().and_then(|image| image.get_size()));
                 }
                 0b0100 => {
                     chooser_container.set_visible_child_name("chooser");
@@ -350,22 +462,21 @@ impl Connect for App {
                 _ => unreachable!(),
             }
 
-            let image_length = image_length.get();
-
-            let tasks = tasks.lock().unwrap();
+            let mut tasks = tasks.lock().unwrap();
             let ntasks = tasks.len();
             if ntasks == 0 {
                 return Continue(true);
             }
 
             let mut finished = true;
-            for (task, &(ref bar, ref label)) in tasks.deref().iter().zip(bars.borrow().iter()) {
-                let raw_value = task.progress.load(Ordering::SeqCst);
-                let value = if task.finished.load(Ordering::SeqCst) == 1 {
+            for (task, &(ref bar, ref label)) in tasks.deref_mut().iter_mut().zip(bars.borrow().iter()) {
+                task.update();
+                let raw_value = task.progress;
+                let value = if task.finished {
                     1.0f64
                 } else {
                     finished = false;
-                    raw_value as f64 / image_length as f64
+                    raw_value as f64 / task.length as f64
                 };
 
                 bar.set_fraction(value);
@@ -376,55 +487,84 @@ impl Connect for App {
                 prev_values[3] = prev_values[4];
                 prev_values[4] = prev_values[5];
                 prev_values[5] = prev_values[6];
-                prev_values[6] = raw_value - prev_values[0];
+                // Progress starts over once the flash moves on to verifying.
+                prev_values[6] = raw_value.saturating_sub(prev_values[0]);
                 prev_values[0] = raw_value;
 
                 let sum: usize = prev_values.iter().skip(1).sum();
                 let per_second = sum / 3;
-                label.set_label(&if per_second > (1024 * 1024) {
-                    format!("{} MiB/s", per_second / (1024 * 1024))
+                label.set_label(&if !task.is_transferring() {
+                    task.phase.to_owned()
+                } else if per_second > (1024 * 1024) {
+                    format!("{}: {} MiB/s", task.phase, per_second / (1024 * 1024))
                 } else {
-                    format!("{} KiB/s", per_second / 1024)
+                    format!("{}: {} KiB/s", task.phase, per_second / 1024)
                 });
             }
 
             if finished {
                 stack.set_visible_child_name("summary");
+                back.set_visible(false);
                 next.set_label("Close");
                 next.get_style_context()
                     .map(|c| c.remove_class("destructive-action"));
                 next.set_visible(true);
 
-                let mut errored: Vec<(String, DiskError)> = Vec::new();
+                // Each device is listed with whether it is safe to remove, or why it failed.
+                let mut errored = 0;
+                let mut statuses: Vec<(String, String)> = Vec::new();
                 let mut task_handles = task_handles.lock().unwrap();
                 let devices = devices.lock().unwrap();
                 let handle_iter = task_handles.deref_mut().drain(..);
-                let mut device_iter = devices.deref().iter();
-                for handle in handle_iter {
+                let mut device_iter = devices
+                    .deref()
+                    .iter()
+                    .filter(|(_, button)| button.get_active() && button.get_sensitive());
+                for (handle, task) in handle_iter.zip(tasks.iter()) {
                     if let Some(&(ref device, _)) = device_iter.next() {
-                        if let Err(why) = handle.join().unwrap() {
-                            errored.push((device.clone(), why));
-                        }
+                        let status = match handle.join().unwrap() {
+                            Ok(ref flashed) if !flashed.bad_blocks.is_empty() => format!(
+                                "Flashed, but {} writes had to be retried; the drive may be unreliable",
+                                flashed.bad_blocks.len()
+                            ),
+                            Ok(ref flashed) if flashed.ejected => "Safe to remove".into(),
+                            Ok(_) if eject.get_active() => "Flashed, but could not be ejected".into(),
+                            Ok(_) => "Flashed".into(),
+                            Err(DiskError::Cancelled { .. }) => {
+                                errored += 1;
+                                "Cancelled".into()
+                            }
+                            Err(why) => {
+                                errored += 1;
+                                format!("{}", why)
+                            }
+                        };
+                        let status = match task.released() {
+                            Some(released) => format!("{} ({})", status, released),
+                            None => status,
+                        };
+                        statuses.push((device.clone(), status));
                     }
                 }
 
-                if errored.is_empty() {
+                if errored == 0 {
                     description.set_text(&format!("{} devices successfully flashed", ntasks));
                 } else {
                     description.set_text(&format!(
                         "{} of {} devices successfully flashed",
-                        ntasks - errored.len(),
+                        ntasks - errored,
                         ntasks
                     ));
-                    list.set_visible(true);
-                    for (device, why) in errored {
-                        let container = Box::new(Orientation::Horizontal, 0);
-                        let device = Label::new(device.as_str());
-                        let why = Label::new(format!("{}", why).as_str());
-                        container.pack_start(&device, false, false, 0);
-                        container.pack_start(&why, true, true, 0);
-                        list.insert(&container, -1);
-                    }
+                }
+
+                list.set_visible(true);
+                for (device, status) in statuses {
+                    let container = Box::new(Orientation::Horizontal, 0);
+                    let device = Label::new(device.as_str());
+                    let status = Label::new(status.as_str());
+                    container.pack_start(&device, false, false, 0);
+                    container.pack_start(&status, true, true, 0);
+                    list.insert(&container, -1);
                 }
 
                 Continue(false)
@@ -434,3 +574,65 @@ impl Connect for App {
         });
     }
 }
+
+/// Creates the check button for selecting a device, which is disabled if the device cannot
+/// be flashed with the image.
+fn device_button(device: &Device, image_size: Option<u64>, system: &SystemDisks) -> CheckButton {
+    let path = device.path.to_string_lossy();
+    let label = device.label();
+    let button = if label.is_empty() {
+        CheckButton::new_with_label(&path)
+    } else {
+        CheckButton::new_with_label(&[&label, " (", &path, ")"].concat())
+    };
+
+    if image_size.is_some_and(|image_size| device.size < image_size) {
+        button.set_sensitive(false);
+        button.set_tooltip_text("This drive is too small for the image");
+    }
+
+    if let Some(holds) = system.holds(&device.path) {
+        button.set_sensitive(false);
+        button.set_tooltip_text(format!("This drive holds {}", holds).as_str());
+    }
+
+    match popsicle::is_read_only(&device.path) {
+        Ok(true) => {
+            button.set_sensitive(false);
+            button.set_tooltip_text("This drive is write-protected");
+        }
+        Ok(false) => (),
+        Err(why) => eprintln!(
+            "popsicle-gtk: unable to check if {} is writable: {}",
+            path, why
+        ),
+    }
+
+    button
+}
+
+/// Shows why the selected devices could not be prepared for flashing, along with the
+/// processes which are using a device, if it is busy.
+fn show_disk_error(why: &DiskError) {
+    let mut message = format!("{}", why);
+    if let Some(Ok(processes)) = why.busy_disk().map(Process::holding) {
+        for process in processes {
+            message.push_str(&format!(
+                "\n{} ({}) is using {}",
+                process.command,
+                process.pid,
+                process.path.display()
+            ));
+        }
+    }
+
+    let dialog = MessageDialog::new(
+        None::<&Window>,
+        DialogFlags::MODAL,
+        MessageType::Error,
+        ButtonsType::Close,
+        &message,
+    );
+    dialog.run();
+    dialog.destroy();
+}
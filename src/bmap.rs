@@ -0,0 +1,346 @@
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+#[rustfmt::skip]
+#[derive(Debug, Fail)]
+pub enum BmapError {
+    #[fail(display = "unable to read block map: {}", why)]
+    Read { why: io::Error },
+    #[fail(display = "block map is missing the {} element", element)]
+    Missing { element: &'static str },
+    #[fail(display = "block map has an invalid {}: '{}'", element, value)]
+    Invalid { element: &'static str, value: String },
+    #[fail(display = "block map uses the unsupported checksum type '{}'", checksum)]
+    UnsupportedChecksum { checksum: String },
+    #[fail(display = "block map does not match its own checksum")]
+    Corrupt,
+}
+
+/// A range of bytes in the image which contain data, and the SHA-256 sum of
+/// that data, if the block map provided one.
+#[derive(Clone, Debug)]
+pub struct BlockRange {
+    pub start:    u64,
+    pub end:      u64,
+    pub checksum: Option<[u8; 32]>,
+}
+
+/// The block map of an image, as generated by `bmaptool create`.
+///
+/// Only the mapped ranges of the image need to be written to a disk, which
+/// can be far quicker than writing an image that is mostly empty space.
+#[derive(Clone, Debug)]
+pub struct BlockMap {
+    image_size: u64,
+    block_size: u64,
+    ranges:     Vec<BlockRange>,
+}
+
+impl BlockMap {
+    /// Reads and parses the block map at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<BlockMap, BmapError> {
+        let mut xml = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut xml))
+            .map_err(|why| BmapError::Read { why })?;
+
+        BlockMap::parse(&xml)
+    }
+
+    /// Parses a block map from its XML description.
+    pub fn parse(xml: &str) -> Result<BlockMap, BmapError> {
+        // Block maps before version 2.0 did not name their checksum type, as
+        // they only ever used SHA-1.
+        let checksum_type = match element(xml, "ChecksumType") {
+            Some(checksum_type) => checksum_type,
+            None if version(xml).is_some_and(|major| major < 2) => "sha1",
+            None => "sha256",
+        };
+
+        if checksum_type != "sha256" {
+            return Err(BmapError::UnsupportedChecksum {
+                checksum: checksum_type.into(),
+            });
+        }
+
+        if let Some(expected) = element(xml, "BmapFileChecksum") {
+            // The checksum of the file is taken with its own value zeroed out.
+            let zeroed = xml.replacen(expected, &"0".repeat(expected.len()), 1);
+            if hex(&Sha256::digest(zeroed.as_bytes())) != expected {
+                return Err(BmapError::Corrupt);
+            }
+        }
+
+        let image_size = number(xml, "ImageSize")?;
+        let block_size = number(xml, "BlockSize")?;
+        if block_size == 0 {
+            return Err(BmapError::Invalid {
+                element: "BlockSize",
+                value:   "0".into(),
+            });
+        }
+
+        let block_map = element(xml, "BlockMap").ok_or(BmapError::Missing {
+            element: "BlockMap",
+        })?;
+
+        let mut ranges: Vec<BlockRange> = Vec::new();
+        for (attributes, value) in ranges_of(block_map) {
+            let invalid = || BmapError::Invalid {
+                element: "Range",
+                value:   value.into(),
+            };
+
+            let mut blocks = value.splitn(2, '-').map(|block| block.trim().parse::<u64>());
+            let first = blocks.next().and_then(Result::ok).ok_or_else(invalid)?;
+            let last = match blocks.next() {
+                Some(block) => block.map_err(|_| invalid())?,
+                None => first,
+            };
+
+            let start = first.checked_mul(block_size).ok_or_else(invalid)?;
+            let end = last
+                .checked_add(1)
+                .and_then(|blocks| blocks.checked_mul(block_size))
+                .ok_or_else(invalid)?
+                .min(image_size);
+            let out_of_order = ranges.last().is_some_and(|prev| prev.end > start);
+            if last < first || start >= image_size || out_of_order {
+                return Err(invalid());
+            }
+
+            let checksum = match attribute(attributes, "chksum") {
+                Some(sum) => Some(parse_sha256(sum).ok_or_else(invalid)?),
+                None => None,
+            };
+
+            ranges.push(BlockRange {
+                start,
+                end,
+                checksum,
+            });
+        }
+
+        Ok(BlockMap {
+            image_size,
+            block_size,
+            ranges,
+        })
+    }
+
+    /// The size of the image that this block map describes.
+    pub fn image_size(&self) -> u64 { self.image_size }
+
+    pub fn block_size(&self) -> u64 { self.block_size }
+
+    /// The mapped ranges of the image, in ascending order.
+    pub fn ranges(&self) -> &[BlockRange] { &self.ranges }
+
+    /// The number of bytes which are contained within the mapped ranges.
+    pub fn mapped_size(&self) -> u64 { self.ranges.iter().map(|r| r.end - r.start).sum() }
+}
+
+/// Returns the trimmed text within the first `<tag>` element.
+fn element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = ["<", tag, ">"].concat();
+    let close = ["</", tag, ">"].concat();
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&close)?;
+    Some(xml[start..end].trim())
+}
+
+/// The major version of the block map format, from the `<bmap>` element.
+fn version(xml: &str) -> Option<u32> {
+    let start = xml.find("<bmap ")?;
+    let end = start + xml[start..].find('>')?;
+    let version = attribute(&xml[start..end], "version")?;
+    version.split('.').next()?.trim().parse::<u32>().ok()
+}
+
+fn number(xml: &str, tag: &'static str) -> Result<u64, BmapError> {
+    let value = element(xml, tag).ok_or(BmapError::Missing { element: tag })?;
+    value.parse::<u64>().map_err(|_| BmapError::Invalid {
+        element: tag,
+        value:   value.into(),
+    })
+}
+
+/// Collects the attributes and the text of each `<Range>` element, skipping
+/// over any comments.
+fn ranges_of(mut xml: &str) -> Vec<(&str, &str)> {
+    let mut ranges = Vec::new();
+    loop {
+        let comment = xml.find("<!--");
+        let range = xml.find("<Range");
+        match (comment, range) {
+            (Some(comment), Some(range)) if comment < range => match xml[comment..].find("-->") {
+                Some(end) => xml = &xml[comment + end + 3..],
+                None => break,
+            },
+            (_, Some(range)) => {
+                let rest = &xml[range + 6..];
+                let (attributes, rest) = match rest.find('>') {
+                    Some(end) => (&rest[..end], &rest[end + 1..]),
+                    None => break,
+                };
+                let end = match rest.find("</Range>") {
+                    Some(end) => end,
+                    None => break,
+                };
+                ranges.push((attributes, rest[..end].trim()));
+                xml = &rest[end..];
+            }
+            _ => break,
+        }
+    }
+
+    ranges
+}
+
+fn attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    let key = [name, "=\""].concat();
+    let start = attributes.find(&key)? + key.len();
+    let end = start + attributes[start..].find('"')?;
+    Some(&attributes[start..end])
+}
+
+fn parse_sha256(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut sum = [0; 32];
+    for (byte, pair) in sum.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(::std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+
+    Some(sum)
+}
+
+fn hex(data: &[u8]) -> String { data.iter().map(|byte| format!("{:02x}", byte)).collect() }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUM: &str = "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d";
+
+    /// A block map of ten 4 KiB blocks, the last of which is only partly used.
+    fn bmap(ranges: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\" ?>
+<bmap version=\"2.0\">
+    <ImageSize> 39000 </ImageSize>
+    <BlockSize> 4096 </BlockSize>
+    <BlocksCount> 10 </BlocksCount>
+    <MappedBlocksCount> 4 </MappedBlocksCount>
+    <ChecksumType> sha256 </ChecksumType>
+    <BmapFileChecksum> {} </BmapFileChecksum>
+    <BlockMap>
+{}
+    </BlockMap>
+</bmap>
+",
+            "0".repeat(64),
+            ranges
+        )
+    }
+
+    /// Fills in the checksum of the block map, as `bmaptool create` does.
+    fn signed(ranges: &str) -> String {
+        let xml = bmap(ranges);
+        let sum = hex(&Sha256::digest(xml.as_bytes()));
+        xml.replacen(&"0".repeat(64), &sum, 1)
+    }
+
+    /// Omits the checksum of the block map, as versions before 1.4 did.
+    fn unsigned(ranges: &str) -> String {
+        bmap(ranges)
+            .lines()
+            .filter(|line| !line.contains("BmapFileChecksum"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn ranges() {
+        let xml = signed(&format!(
+            "        <!-- <Range> 7 </Range> -->
+        <Range chksum=\"{}\"> 0-1 </Range>
+        <Range> 5 </Range>
+        <Range> 9 </Range>",
+            SUM
+        ));
+
+        let map = BlockMap::parse(&xml).unwrap();
+        assert_eq!(map.image_size(), 39000);
+        assert_eq!(map.block_size(), 4096);
+
+        let ranges: Vec<_> = map.ranges().iter().map(|r| (r.start, r.end)).collect();
+        assert_eq!(ranges, vec![(0, 8192), (20480, 24576), (36864, 39000)]);
+        assert_eq!(map.ranges()[0].checksum, parse_sha256(SUM));
+        assert!(map.ranges()[1].checksum.is_none());
+        assert_eq!(map.mapped_size(), 8192 + 4096 + 2136);
+    }
+
+    #[test]
+    fn checksum() {
+        let xml = signed("        <Range> 0-1 </Range>");
+        assert!(BlockMap::parse(&xml).is_ok());
+
+        match BlockMap::parse(&xml.replace("0-1", "0-2")) {
+            Err(BmapError::Corrupt) => (),
+            other => panic!("tampered block map was accepted: {:?}", other),
+        }
+
+        let xml = unsigned("        <Range> 0-2 </Range>");
+        assert_eq!(BlockMap::parse(&xml).unwrap().mapped_size(), 3 * 4096);
+    }
+
+    #[test]
+    fn invalid() {
+        let out_of_order = unsigned("        <Range> 5 </Range>\n        <Range> 0-1 </Range>");
+        match BlockMap::parse(&out_of_order) {
+            Err(BmapError::Invalid { element: "Range", ref value }) if value == "0-1" => (),
+            other => panic!("out of order ranges were accepted: {:?}", other),
+        }
+
+        assert!(BlockMap::parse(&unsigned("        <Range> 10 </Range>")).is_err());
+        assert!(BlockMap::parse(&unsigned("        <Range> 3-2 </Range>")).is_err());
+
+        // Ranges whose offsets do not fit are rejected, rather than wrapping around.
+        let huge = format!("        <Range> {} </Range>", u64::MAX / 4096 + 1);
+        assert!(BlockMap::parse(&unsigned(&huge)).is_err());
+        let huge = format!("        <Range> 0-{} </Range>", u64::MAX);
+        assert!(BlockMap::parse(&unsigned(&huge)).is_err());
+        let huge = format!("        <Range> 0-{} </Range>", u64::MAX / 4096);
+        assert!(BlockMap::parse(&unsigned(&huge)).is_err());
+    }
+
+    #[test]
+    fn checksum_types() {
+        let sha1 = unsigned("").replace("sha256", "sha1");
+        match BlockMap::parse(&sha1) {
+            Err(BmapError::UnsupportedChecksum { ref checksum }) if checksum == "sha1" => (),
+            other => panic!("unsupported checksum was accepted: {:?}", other),
+        }
+
+        // Without a checksum type, it is implied by the version of the format.
+        let untyped = unsigned("        <Range> 0-1 </Range>")
+            .lines()
+            .filter(|line| !line.contains("ChecksumType"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(BlockMap::parse(&untyped).unwrap().mapped_size(), 2 * 4096);
+
+        for old in &["1.4", "1.3", "1.0"] {
+            let xml = untyped.replace("version=\"2.0\"", &format!("version=\"{}\"", old));
+            match BlockMap::parse(&xml) {
+                Err(BmapError::UnsupportedChecksum { ref checksum }) if checksum == "sha1" => (),
+                other => panic!("version {} block map was accepted: {:?}", old, other),
+            }
+        }
+    }
+}
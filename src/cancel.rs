@@ -0,0 +1,36 @@
+//! Cancels flashes which are in progress, from other threads.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A token which is checked by a flash between each chunk that it writes or
+/// verifies, so that the flash may be stopped without killing the process.
+///
+/// Clones of a token share its state. A child token is cancelled along with
+/// its parent, but may also be cancelled alone, so that a single disk may be
+/// stopped while others are being flashed.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    flag:   Arc<AtomicBool>,
+    parent: Option<Box<CancelToken>>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken { CancelToken::default() }
+
+    /// A token which is cancelled when either it, or this token, is cancelled.
+    pub fn child(&self) -> CancelToken {
+        CancelToken {
+            flag:   Arc::default(),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+
+    /// Cancels this token, its clones, and its children.
+    pub fn cancel(&self) { self.flag.store(true, Ordering::SeqCst); }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+            || self.parent.as_ref().is_some_and(|parent| parent.is_cancelled())
+    }
+}
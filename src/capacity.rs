@@ -0,0 +1,205 @@
+//! Detects counterfeit drives, which claim to hold more than they can, by
+//! writing tagged blocks across the device and reading them back.
+
+use std::io;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::disk::Disk;
+
+/// How many blocks are spread across the device.
+const PROBES: u64 = 256;
+
+/// What the probe needs of a disk, so that it may also be run against disks
+/// which only pretend to hold what they claim to.
+pub(crate) trait Probe {
+    fn block_size(&self) -> usize;
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()>;
+
+    /// Syncs what was written, and ensures that it is read back from the
+    /// device itself, rather than from a cache.
+    fn settle(&mut self) -> io::Result<()>;
+}
+
+impl Probe for Disk {
+    fn block_size(&self) -> usize { Disk::block_size(self) }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        Disk::write_at(self, offset, data)
+    }
+
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        Disk::read_at(self, offset, buffer)
+    }
+
+    fn settle(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.bypass_cache()
+    }
+}
+
+/// Writes a uniquely tagged block at positions spread across the `size` bytes
+/// that the disk advertises, and reads each of them back. Returns how many
+/// bytes are actually usable, if the disk cannot hold what it advertises.
+///
+/// Counterfeit drives typically wrap around, storing the blocks beyond their
+/// real capacity over those before it. The blocks are therefore written from
+/// the end of the disk to the start, so that each block beyond the real
+/// capacity is read back as another block, or as nothing at all.
+///
+/// As the real capacity of such drives is a power of two, from which they
+/// drop the higher bits of each address, blocks are also written at each
+/// power of two. Those beyond the real capacity wrap to the start of the disk,
+/// where another block was written, rather than to where there is none.
+pub(crate) fn probe<D: Probe>(disk: &mut D, size: u64) -> io::Result<Option<u64>> {
+    let block = disk.block_size() as u64;
+    if size < block {
+        return Ok(None);
+    }
+
+    let spread = (0..PROBES).map(|probe| size / PROBES * probe / block * block);
+    let powers = (0..64)
+        .map(|bit| 1u64 << bit)
+        .skip_while(|&power| power < block)
+        .take_while(|&power| power <= size - block);
+
+    let mut positions: Vec<u64> = spread.chain(powers).collect();
+    positions.push((size - block) / block * block);
+    positions.sort_unstable();
+    positions.dedup();
+
+    let nonce = nonce();
+    let mut buffer = vec![0; block as usize];
+    let mut expected = vec![0; block as usize];
+
+    // Drives may also refuse to write beyond their real capacity.
+    let mut refused = vec![false; positions.len()];
+    for (index, &position) in positions.iter().enumerate().rev() {
+        tag(&mut buffer, nonce, position);
+        refused[index] = disk.write_at(position, &buffer).is_err();
+    }
+
+    disk.settle()?;
+
+    for (index, &position) in positions.iter().enumerate() {
+        if refused[index] {
+            return Ok(Some(position));
+        }
+
+        tag(&mut expected, nonce, position);
+        match disk.read_at(position, &mut buffer) {
+            Ok(()) if buffer == expected => (),
+            _ => return Ok(Some(position)),
+        }
+    }
+
+    Ok(None)
+}
+
+/// Fills the block with a pattern which is unique to its position, and to
+/// this probe, so that blocks left by an earlier probe are not mistaken for it.
+fn tag(block: &mut [u8], nonce: u64, position: u64) {
+    let mut state = (nonce ^ position.wrapping_mul(0x9E37_79B9_7F4A_7C15)) | 1;
+    for word in block.chunks_mut(8) {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let bytes = state.to_le_bytes();
+        word.copy_from_slice(&bytes[..word.len()]);
+    }
+
+    block[..8].copy_from_slice(&position.to_le_bytes());
+}
+
+fn nonce() -> u64 {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or(0);
+    time ^ (u64::from(process::id()) << 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const BLOCK: usize = 512;
+
+    /// A drive which only stores `real` bytes, wrapping each address beyond
+    /// them around to the start, or refusing to write beyond them.
+    struct FakeDisk {
+        real:   u64,
+        refuse: bool,
+        blocks: HashMap<u64, Vec<u8>>,
+    }
+
+    impl FakeDisk {
+        fn new(real: u64) -> FakeDisk {
+            FakeDisk {
+                real,
+                refuse: false,
+                blocks: HashMap::new(),
+            }
+        }
+    }
+
+    impl Probe for FakeDisk {
+        fn block_size(&self) -> usize { BLOCK }
+
+        fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+            if self.refuse && offset >= self.real {
+                return Err(io::Error::from_raw_os_error(::libc::EIO));
+            }
+
+            self.blocks.insert(offset % self.real, data.to_vec());
+            Ok(())
+        }
+
+        fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+            match self.blocks.get(&(offset % self.real)) {
+                Some(block) => buffer.copy_from_slice(block),
+                None => buffer.iter_mut().for_each(|byte| *byte = 0),
+            }
+            Ok(())
+        }
+
+        fn settle(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    /// What a "64 GB" drive typically advertises.
+    const ADVERTISED: u64 = 62_008_590_336;
+
+    #[test]
+    fn genuine() {
+        let mut disk = FakeDisk::new(ADVERTISED);
+        assert_eq!(probe(&mut disk, ADVERTISED).unwrap(), None);
+    }
+
+    #[test]
+    fn wrapping() {
+        // None of the evenly spread blocks beyond 8 GiB wrap onto another.
+        let mut disk = FakeDisk::new(1 << 33);
+        assert_eq!(probe(&mut disk, ADVERTISED).unwrap(), Some(1 << 33));
+
+        let mut disk = FakeDisk::new(1 << 30);
+        assert_eq!(probe(&mut disk, ADVERTISED).unwrap(), Some(1 << 30));
+    }
+
+    #[test]
+    fn refusing() {
+        let mut disk = FakeDisk::new(1 << 33);
+        disk.refuse = true;
+        assert_eq!(probe(&mut disk, ADVERTISED).unwrap(), Some(1 << 33));
+    }
+
+    #[test]
+    fn too_small() {
+        let mut disk = FakeDisk::new(BLOCK as u64);
+        assert_eq!(probe(&mut disk, BLOCK as u64 - 1).unwrap(), None);
+    }
+}
@@ -0,0 +1,254 @@
+use libc;
+use std::fmt;
+use std::fs::{self, canonicalize, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+pub(crate) const SYS_BLOCK: &str = "/sys/class/block/";
+
+/// The bus that a block device is attached through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    Usb,
+    /// SD and MMC cards, in a reader that is not attached by USB.
+    Mmc,
+    Nvme,
+    Ata,
+    /// Devices which are backed by software, such as loop devices.
+    Virtual,
+    Other,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Transport::Usb => "usb",
+            Transport::Mmc => "mmc",
+            Transport::Nvme => "nvme",
+            Transport::Ata => "ata",
+            Transport::Virtual => "virtual",
+            Transport::Other => "other",
+        })
+    }
+}
+
+/// A block device, as described by sysfs.
+#[derive(Clone, Debug)]
+pub struct Device {
+    /// The name of the device within `/sys/class/block`, such as `sdb`.
+    pub name:        String,
+    pub path:        PathBuf,
+    pub vendor:      String,
+    pub model:       String,
+    pub serial:      String,
+    /// The capacity of the device, in bytes.
+    pub size:        u64,
+    pub removable:   bool,
+    pub transport:   Transport,
+    pub sector_size: u64,
+}
+
+impl Device {
+    /// Enumerates every whole disk on the system. Partitions are skipped, as
+    /// are devices without any media, such as empty card readers.
+    pub fn all() -> io::Result<Vec<Device>> {
+        let mut devices = Vec::new();
+        for entry in fs::read_dir(SYS_BLOCK)? {
+            let entry = entry?;
+            if entry.path().join("partition").exists() {
+                continue;
+            }
+
+            // Devices may disappear while they are being enumerated.
+            let name = entry.file_name();
+            if let Some(Ok(device)) = name.to_str().map(Device::from_name) {
+                if device.size != 0 {
+                    devices.push(device);
+                }
+            }
+        }
+
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(devices)
+    }
+
+    /// Describes the block device at the given path, such as `/dev/sdb`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> io::Result<Device> {
+        let path = canonicalize(path)?;
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a block device"))?;
+        Device::from_name(name)
+    }
+
+    pub(crate) fn from_name(name: &str) -> io::Result<Device> { Device::from_sys(Path::new(SYS_BLOCK), name) }
+
+    /// Describes the block device with the given name, from `sys_block`,
+    /// which stands in for `/sys/class/block`.
+    pub(crate) fn from_sys(sys_block: &Path, name: &str) -> io::Result<Device> {
+        let sys = sys_block.join(name);
+        let real = canonicalize(&sys)?;
+        let size = read(&sys.join("size"))
+            .parse::<u64>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid device size"))?;
+
+        Ok(Device {
+            name:        name.into(),
+            path:        PathBuf::from("/dev").join(name),
+            vendor:      read(&sys.join("device/vendor")),
+            model:       model(&sys),
+            serial:      serial(&real),
+            // The size is always counted in 512-byte sectors.
+            size:        size * 512,
+            removable:   read(&sys.join("removable")) == "1",
+            transport:   transport(name, &real),
+            sector_size: read(&sys.join("queue/logical_block_size"))
+                .parse::<u64>()
+                .unwrap_or(512),
+        })
+    }
+
+    /// Whether the device is a USB drive or a memory card, which are what
+    /// images are usually flashed to.
+    pub fn is_portable(&self) -> bool {
+        self.transport == Transport::Usb || self.transport == Transport::Mmc
+    }
+
+    /// Detaches the device from the system, so that it is safe to unplug.
+    ///
+    /// The device is flushed, and then deleted from the SCSI layer, which has
+    /// the drive write back its own cache. The USB device which it belongs to
+    /// is then powered off, which also detaches the other units of a card
+    /// reader. Devices which cannot be detached, such as built-in card
+    /// readers, are only flushed, and an error is returned.
+    ///
+    /// A USB device with other units that are in use, such as a card reader
+    /// with another card being flashed, is only flushed, and an error is
+    /// returned, rather than cutting the other units off.
+    pub fn eject(&self) -> io::Result<()> {
+        File::open(&self.path)?.sync_all()?;
+
+        // The USB device must be found before the block device is deleted.
+        let sys = canonicalize(PathBuf::from(SYS_BLOCK).join(&self.name))?;
+        let usb = sys
+            .ancestors()
+            .find(|dir| dir.join("idVendor").exists())
+            .filter(|dir| dir.join("remove").exists());
+
+        if let Some(usb) = usb {
+            if let Some(unit) = self.busy_sibling(usb)? {
+                return Err(io::Error::other(format!(
+                    "device cannot be detached while '{}' is in use",
+                    unit
+                )));
+            }
+        }
+
+        let usb = usb.map(|dir| dir.join("remove"));
+
+        let delete = sys.join("device/delete");
+        let deleted = delete.exists();
+        if deleted {
+            fs::write(&delete, "1")?;
+        }
+
+        match usb {
+            Some(remove) => fs::write(remove, "1"),
+            None if deleted => Ok(()),
+            None => Err(io::Error::other("device cannot be detached")),
+        }
+    }
+
+    /// Finds another disk within the USB device at `usb`, which is mounted or
+    /// held exclusively, as a disk which is being flashed is.
+    fn busy_sibling(&self, usb: &Path) -> io::Result<Option<String>> {
+        for entry in fs::read_dir(SYS_BLOCK)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name == self.name || entry.path().join("partition").exists() {
+                continue;
+            }
+
+            let within = canonicalize(entry.path()).is_ok_and(|sys| sys.starts_with(usb));
+            if within && is_claimed(&Path::new("/dev").join(&name)) {
+                return Ok(Some(name));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// A description of the device for display, made from its vendor and model.
+    pub fn label(&self) -> String {
+        if self.vendor.is_empty() {
+            self.model.replace('_', " ")
+        } else {
+            [&self.vendor, " ", &self.model].concat().replace('_', " ")
+        }
+    }
+}
+
+/// Whether the block device is mounted, or held exclusively by another
+/// process, in which case it cannot be opened exclusively.
+fn is_claimed(path: &Path) -> bool {
+    let opened = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_EXCL)
+        .open(path);
+    match opened {
+        Err(ref why) => why.raw_os_error() == Some(libc::EBUSY),
+        Ok(_) => false,
+    }
+}
+
+/// Reads a sysfs attribute, which will be empty if the device lacks it.
+fn read(path: &Path) -> String {
+    fs::read_to_string(path)
+        .map(|value| value.trim().to_owned())
+        .unwrap_or_default()
+}
+
+/// SCSI and NVMe devices have a model, whereas memory cards have a name.
+fn model(sys: &Path) -> String {
+    let model = read(&sys.join("device/model"));
+    if model.is_empty() {
+        read(&sys.join("device/name"))
+    } else {
+        model
+    }
+}
+
+/// Memory cards and NVMe drives have a serial of their own, whereas USB
+/// drives report the serial of the USB device which they belong to.
+fn serial(real: &Path) -> String {
+    for dir in real.ancestors().skip(1) {
+        let serial = read(&dir.join("serial"));
+        // USB hubs have serials too, so the search ends at the USB device.
+        if !serial.is_empty() || dir.join("idVendor").exists() || dir == Path::new("/sys/devices") {
+            return serial;
+        }
+    }
+
+    String::new()
+}
+
+/// Determines the transport from where the device sits within sysfs, for
+/// example `/sys/devices/pci0000:00/0000:00:14.0/usb2/2-1/.../block/sdb`.
+fn transport(name: &str, real: &Path) -> Transport {
+    let real = real.to_string_lossy();
+    if real.starts_with("/sys/devices/virtual/") {
+        Transport::Virtual
+    } else if real.contains("/usb") {
+        Transport::Usb
+    } else if name.starts_with("mmcblk") || real.contains("/mmc_host/") {
+        Transport::Mmc
+    } else if name.starts_with("nvme") {
+        Transport::Nvme
+    } else if real.contains("/ata") {
+        Transport::Ata
+    } else {
+        Transport::Other
+    }
+}
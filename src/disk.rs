@@ -0,0 +1,346 @@
+//! Writes to disks with direct I/O, so that flashing does not fill the page cache.
+
+use libc;
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use super::stream::{aligned, BUFFER_SIZE};
+
+const BLKROGET: libc::c_ulong = 0x125E;
+const BLKRRPART: libc::c_ulong = 0x125F;
+const BLKFLSBUF: libc::c_ulong = 0x1261;
+const BLKSSZGET: libc::c_ulong = 0x1268;
+const BLKGETSIZE64: libc::c_ulong = 0x8008_1272;
+
+/// How many times to ask for the partition table to be re-read while the
+/// device is busy, such as while udev is probing it.
+const REREAD_ATTEMPTS: u32 = 5;
+const REREAD_DELAY: Duration = Duration::from_millis(500);
+
+/// A disk which has been opened for flashing.
+///
+/// The disk is opened with `O_DIRECT` where possible, which requires that
+/// the memory, offset, and length of each transfer be aligned to the
+/// logical block size of the device. Data which is not aligned is passed
+/// through an aligned buffer, and partial blocks are read, modified, and
+/// written back. If direct I/O is refused, the disk is opened with `O_SYNC`.
+///
+/// The disk is opened with `O_EXCL`, which claims the device for as long as
+/// the disk is held, so that automounters and the kernel cannot mount its
+/// partitions while it is being written and verified.
+pub struct Disk {
+    path:       PathBuf,
+    file:       File,
+    /// The handle which claimed the device, once the disk has been reopened.
+    claim:      Option<File>,
+    direct:     bool,
+    block_size: usize,
+    bounce:     Vec<u8>,
+}
+
+impl Disk {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Disk> {
+        let path = path.as_ref();
+        let (file, direct) = match open(path, libc::O_DIRECT | libc::O_EXCL) {
+            Ok(file) => (file, true),
+            Err(ref why) if why.raw_os_error() == Some(libc::EINVAL) => {
+                (open(path, libc::O_SYNC | libc::O_EXCL)?, false)
+            }
+            Err(why) => return Err(why),
+        };
+
+        let block_size = logical_block_size(&file);
+        Ok(Disk {
+            path: path.to_path_buf(),
+            file,
+            claim: None,
+            direct,
+            block_size,
+            bounce: Vec::new(),
+        })
+    }
+
+    /// Whether writes to the disk bypass the page cache.
+    pub fn is_direct(&self) -> bool { self.direct }
+
+    /// The logical block size of the device, which direct I/O is aligned to.
+    pub fn block_size(&self) -> usize { self.block_size }
+
+    /// The capacity of the device, in bytes.
+    pub fn size(&self) -> io::Result<u64> { size_of(&self.file) }
+
+    pub(crate) fn file(&self) -> &File { &self.file }
+
+    /// Writes all of the data at `offset`.
+    pub(crate) fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if self.direct {
+            match self.transfer(offset, data.len(), Transfer::Write(data)) {
+                Err(ref why) if why.raw_os_error() == Some(libc::EINVAL) => self.fall_back()?,
+                result => return result,
+            }
+        }
+
+        self.file.write_all_at(data, offset)
+    }
+
+    /// Fills the buffer with the data at `offset`.
+    pub(crate) fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        if self.direct {
+            let len = buffer.len();
+            match self.transfer(offset, len, Transfer::Read(&mut *buffer)) {
+                Err(ref why) if why.raw_os_error() == Some(libc::EINVAL) => self.fall_back()?,
+                result => return result,
+            }
+        }
+
+        self.file.read_exact_at(buffer, offset)
+    }
+
+    /// Waits for everything written to reach the device.
+    pub(crate) fn flush(&mut self) -> io::Result<()> { self.file.sync_all() }
+
+    /// Makes everything which was written durable, and has the kernel read
+    /// the partition table that was written. Returns whether the partition
+    /// table was re-read, which is not possible for devices that cannot be
+    /// partitioned, or which remain busy.
+    pub(crate) fn finish(&mut self) -> io::Result<bool> {
+        self.file.sync_all()?;
+
+        // Only the handle which claimed the device may re-read its partitions
+        // while the device is claimed.
+        let fd = self.claim.as_ref().unwrap_or(&self.file).as_raw_fd();
+
+        // Flushing requires `CAP_SYS_ADMIN`, and merely drops what is cached.
+        unsafe {
+            libc::ioctl(fd, BLKFLSBUF as _, 0);
+        }
+
+        for attempt in 1..=REREAD_ATTEMPTS {
+            if unsafe { libc::ioctl(fd, BLKRRPART as _, 0) } == 0 {
+                return Ok(true);
+            }
+
+            match io::Error::last_os_error().raw_os_error() {
+                Some(libc::EBUSY) if attempt < REREAD_ATTEMPTS => thread::sleep(REREAD_DELAY),
+                _ => break,
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Whether the device can hold partitions, which loop devices only do if
+    /// they were set up to scan for them.
+    pub fn is_partitionable(&self) -> bool {
+        let device = match sysfs_path(&self.file) {
+            Some(device) => device,
+            None => return false,
+        };
+
+        let read = |name: &str| {
+            fs::read_to_string(device.join(name))
+                .ok()
+                .and_then(|value| value.trim().parse::<u32>().ok())
+        };
+
+        read("ext_range").is_some_and(|range| range > 1)
+            && read("loop/partscan").is_none_or(|partscan| partscan != 0)
+    }
+
+    /// The partitions which the kernel has found on the disk.
+    pub fn partitions(&self) -> Vec<PathBuf> {
+        let device = match sysfs_path(&self.file) {
+            Some(device) => device,
+            None => return Vec::new(),
+        };
+
+        let mut partitions: Vec<PathBuf> = fs::read_dir(device)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join("partition").exists())
+            .map(|entry| Path::new("/dev").join(entry.file_name()))
+            .collect();
+
+        partitions.sort();
+        partitions
+    }
+
+    /// Ensures that what is read back comes from the device, rather than from
+    /// the page cache. The disk is reopened for direct I/O if it was not
+    /// already, and the cached pages of the device are written out and
+    /// dropped, in case direct reads are refused.
+    pub(crate) fn bypass_cache(&mut self) -> io::Result<()> {
+        if !self.direct && self.reopen(libc::O_DIRECT).is_ok() {
+            self.direct = true;
+        }
+
+        // Flushing the buffers of a block device requires `CAP_SYS_ADMIN`,
+        // whereas the advice to drop the pages may be ignored by the kernel.
+        let fd = self.file.as_raw_fd();
+        let flushed = unsafe { libc::ioctl(fd, BLKFLSBUF as _, 0) } == 0;
+        let error = if flushed { None } else { Some(io::Error::last_os_error()) };
+        let advised = unsafe { libc::posix_fadvise(fd, 0, 0, libc::POSIX_FADV_DONTNEED) } == 0;
+
+        match error {
+            Some(why) if !self.direct && !advised => Err(why),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reopens the disk with `O_SYNC`, for devices which accepted `O_DIRECT`
+    /// when opened, but then refused a direct transfer.
+    fn fall_back(&mut self) -> io::Result<()> {
+        self.reopen(libc::O_SYNC)?;
+        self.direct = false;
+        Ok(())
+    }
+
+    /// Reopens the disk after a failed write, as the error may have left the
+    /// handle unusable, such as when the drive was reset. Each transfer is
+    /// positioned, so there is no offset to restore.
+    pub(crate) fn recover(&mut self) -> io::Result<()> {
+        let flags = if self.direct { libc::O_DIRECT } else { libc::O_SYNC };
+        self.reopen(flags)
+    }
+
+    /// Replaces the handle of the disk with one opened with other flags.
+    /// Another exclusive open would be refused, so the first handle is kept
+    /// open to hold the claim on the device.
+    fn reopen(&mut self, flags: libc::c_int) -> io::Result<()> {
+        let previous = mem::replace(&mut self.file, open(&self.path, flags)?);
+        if self.claim.is_none() {
+            self.claim = Some(previous);
+        }
+        Ok(())
+    }
+
+    /// Transfers `len` bytes at `offset` directly, block by block where the
+    /// transfer is not aligned to the logical block size.
+    fn transfer(&mut self, mut offset: u64, len: usize, mut transfer: Transfer) -> io::Result<()> {
+        let block_size = self.block_size;
+        if self.bounce.is_empty() {
+            self.bounce = vec![0; BUFFER_SIZE + block_size];
+        }
+
+        let mut done = 0;
+        while done < len {
+            let within = (offset % block_size as u64) as usize;
+            let count = if within != 0 || len - done < block_size {
+                // A partial block, which must be transferred as a whole block.
+                let count = cmp::min(block_size - within, len - done);
+                let start = offset - within as u64;
+                let block = aligned(&mut self.bounce, block_size, block_size);
+                self.file.read_exact_at(block, start)?;
+                match transfer {
+                    Transfer::Read(ref mut buffer) => {
+                        buffer[done..done + count].copy_from_slice(&block[within..within + count]);
+                    }
+                    Transfer::Write(data) => {
+                        block[within..within + count].copy_from_slice(&data[done..done + count]);
+                        self.file.write_all_at(block, start)?;
+                    }
+                }
+                count
+            } else {
+                let count = (len - done) / block_size * block_size;
+                let count = cmp::min(count, BUFFER_SIZE);
+                match transfer {
+                    Transfer::Read(ref mut buffer) => {
+                        let buffer = &mut buffer[done..done + count];
+                        if buffer.as_ptr().align_offset(block_size) == 0 {
+                            self.file.read_exact_at(buffer, offset)?;
+                        } else {
+                            let bounce = aligned(&mut self.bounce, block_size, count);
+                            self.file.read_exact_at(bounce, offset)?;
+                            buffer.copy_from_slice(bounce);
+                        }
+                    }
+                    Transfer::Write(data) => {
+                        let data = &data[done..done + count];
+                        if data.as_ptr().align_offset(block_size) == 0 {
+                            self.file.write_all_at(data, offset)?;
+                        } else {
+                            let bounce = aligned(&mut self.bounce, block_size, count);
+                            bounce.copy_from_slice(data);
+                            self.file.write_all_at(bounce, offset)?;
+                        }
+                    }
+                }
+                count
+            };
+
+            offset += count as u64;
+            done += count;
+        }
+
+        Ok(())
+    }
+}
+
+/// Obtains the capacity of the block device at `path`, in bytes.
+pub fn device_size<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    File::open(path).and_then(|file| size_of(&file))
+}
+
+/// Whether the block device at `path` is read-only, which is the case for
+/// SD cards with their lock switch on, and write-protected drives.
+pub fn is_read_only<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let file = File::open(path)?;
+    let mut read_only: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), BLKROGET as _, &mut read_only) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let sysfs = sysfs_path(&file).and_then(|path| fs::read_to_string(path.join("ro")).ok());
+    Ok(read_only != 0 || sysfs.is_some_and(|ro| ro.trim() == "1"))
+}
+
+/// The directory of the block device within sysfs.
+pub(crate) fn sysfs_path(file: &File) -> Option<PathBuf> {
+    match file.metadata() {
+        Ok(ref metadata) if metadata.file_type().is_block_device() => {
+            let rdev = metadata.rdev();
+            let device = format!("/sys/dev/block/{}:{}", libc::major(rdev), libc::minor(rdev));
+            Some(PathBuf::from(device))
+        }
+        _ => None,
+    }
+}
+
+fn size_of(file: &File) -> io::Result<u64> {
+    let mut size: u64 = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64 as _, &mut size) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(size)
+}
+
+enum Transfer<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+fn open(path: &Path, flags: libc::c_int) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(flags)
+        .open(path)
+}
+
+fn logical_block_size(file: &File) -> usize {
+    let mut size: libc::c_int = 0;
+    match unsafe { libc::ioctl(file.as_raw_fd(), BLKSSZGET as _, &mut size) } {
+        0 if size > 0 => size as usize,
+        _ => 512,
+    }
+}
@@ -0,0 +1,439 @@
+use super::{invalid, read_u32};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+
+#[cfg(feature = "bzip2")]
+use bzip2::read::MultiBzDecoder;
+#[cfg(feature = "gzip")]
+use flate2::read::MultiGzDecoder;
+#[cfg(feature = "xz")]
+use xz2::read::XzDecoder;
+#[cfg(feature = "zstd")]
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+const XZ_MAGIC: &[u8] = &[0xFD, b'7', b'z', b'X', b'Z', 0x00];
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+const ZSTD_MAGIC: u32 = 0xFD2F_B528;
+
+/// The compression that an image was found to be wrapped in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Bzip2,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// Determines the compression from the magic bytes at the start of the image.
+    pub fn detect(header: &[u8]) -> Compression {
+        if header.starts_with(XZ_MAGIC) {
+            Compression::Xz
+        } else if header.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if header.starts_with(BZIP2_MAGIC) {
+            Compression::Bzip2
+        } else if header.len() >= 4 && read_u32(header) == ZSTD_MAGIC {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Whether support for decompressing this format was compiled in.
+    pub fn is_supported(self) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Bzip2 => cfg!(feature = "bzip2"),
+            Compression::Gzip => cfg!(feature = "gzip"),
+            Compression::Xz => cfg!(feature = "xz"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    /// Wraps the file within a reader that decompresses it on the fly.
+    pub(crate) fn decoder(self, file: File) -> io::Result<Box<dyn Read + Send>> {
+        Ok(match self {
+            Compression::None => Box::new(file),
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2 => Box::new(MultiBzDecoder::new(file)),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Box::new(MultiGzDecoder::new(file)),
+            #[cfg(feature = "xz")]
+            Compression::Xz => Box::new(XzDecoder::new_multi_decoder(file)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Box::new(ZstdDecoder::new(file)?),
+            #[allow(unreachable_patterns)]
+            _ => return Err(io::Error::other(format!("{} support was not enabled", self))),
+        })
+    }
+
+    /// Obtains the size of the image after it has been decompressed, if the
+    /// compressed file records it, as the xz index and zstd frame headers do.
+    ///
+    /// Otherwise, the size is only known once the image has been decompressed,
+    /// which is left to when it is written, rather than done twice.
+    /// The file will be positioned at an arbitrary offset afterwards.
+    pub(crate) fn uncompressed_size(self, file: &mut File) -> io::Result<Option<u64>> {
+        match self {
+            Compression::None => file.metadata().map(|m| Some(m.len())),
+            Compression::Xz => xz_size(file).map(Some),
+            Compression::Zstd => zstd_size(file),
+            Compression::Bzip2 | Compression::Gzip => Ok(None),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Compression::None => "uncompressed",
+            Compression::Bzip2 => "bzip2",
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
+
+fn read_uint(data: &[u8]) -> u64 {
+    data.iter()
+        .rev()
+        .fold(0, |acc, &byte| acc << 8 | u64::from(byte))
+}
+
+/// Sums the uncompressed sizes recorded in the index of every stream in an
+/// xz file, walking backwards from the end of the file.
+fn xz_size(file: &mut File) -> io::Result<u64> {
+    let mut end = file.metadata()?.len();
+    let mut total = 0;
+
+    while end > 0 {
+        // Streams may be followed by padding, in multiples of four null bytes.
+        let mut word = [0; 4];
+        file.seek(SeekFrom::Start(end - 4))?;
+        file.read_exact(&mut word)?;
+        if word == [0; 4] {
+            end -= 4;
+            continue;
+        }
+
+        let mut footer = [0; 12];
+        file.seek(SeekFrom::Start(end - 12))?;
+        file.read_exact(&mut footer)?;
+        if &footer[10..] != b"YZ" {
+            return Err(invalid("xz stream footer not found"));
+        }
+
+        let index_size = (u64::from(read_u32(&footer[4..8])) + 1) * 4;
+        let index_start = (end - 12)
+            .checked_sub(index_size)
+            .ok_or_else(|| invalid("xz index is larger than the file"))?;
+
+        let mut index = vec![0; index_size as usize];
+        file.seek(SeekFrom::Start(index_start))?;
+        file.read_exact(&mut index)?;
+        if index[0] != 0 {
+            return Err(invalid("xz index indicator not found"));
+        }
+
+        let mut fields = &index[1..];
+        let records = xz_varint(&mut fields)?;
+        let mut blocks_size = 0;
+        for _ in 0..records {
+            let unpadded = xz_varint(&mut fields)?;
+            total += xz_varint(&mut fields)?;
+            blocks_size += (unpadded + 3) & !3;
+        }
+
+        end = index_start
+            .checked_sub(blocks_size + 12)
+            .ok_or_else(|| invalid("xz stream is larger than the file"))?;
+    }
+
+    Ok(total)
+}
+
+/// Decodes a variable-length integer from the xz index, advancing the slice.
+fn xz_varint(data: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0;
+    for i in 0..9 {
+        let (&byte, rest) = data
+            .split_first()
+            .ok_or_else(|| invalid("xz index is truncated"))?;
+        *data = rest;
+        value |= u64::from(byte & 0x7F) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(invalid("xz index contains an invalid integer"))
+}
+
+/// Sums the content sizes from the header of each zstd frame, skipping over
+/// the blocks of each frame. Returns `None` if any frame omits its size.
+fn zstd_size(file: &mut File) -> io::Result<Option<u64>> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(file);
+    let mut total = 0;
+
+    loop {
+        let mut magic = [0; 4];
+        match reader.read_exact(&mut magic) {
+            Ok(()) => (),
+            Err(ref why) if why.kind() == io::ErrorKind::UnexpectedEof => return Ok(Some(total)),
+            Err(why) => return Err(why),
+        }
+
+        let magic = read_u32(&magic);
+        if magic & 0xFFFF_FFF0 == 0x184D_2A50 {
+            // Skippable frames contain no image data.
+            let mut size = [0; 4];
+            reader.read_exact(&mut size)?;
+            reader.seek_relative(i64::from(read_u32(&size)))?;
+            continue;
+        } else if magic != ZSTD_MAGIC {
+            return Err(invalid("zstd frame magic not found"));
+        }
+
+        let mut descriptor = [0; 1];
+        reader.read_exact(&mut descriptor)?;
+        let descriptor = descriptor[0];
+        let single_segment = descriptor & 0x20 != 0;
+        let checksum = descriptor & 0x04 != 0;
+        let dictionary_size = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+        let content_size_size = match descriptor >> 6 {
+            0 if single_segment => 1,
+            0 => return Ok(None),
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        };
+
+        let window_size = if single_segment { 0 } else { 1 };
+        reader.seek_relative(window_size + dictionary_size)?;
+
+        let mut content_size = [0; 8];
+        reader.read_exact(&mut content_size[..content_size_size])?;
+        total += read_uint(&content_size[..content_size_size]);
+        if content_size_size == 2 {
+            total += 256;
+        }
+
+        loop {
+            let mut header = [0; 3];
+            reader.read_exact(&mut header)?;
+            let header = read_uint(&header);
+            let last = header & 1 != 0;
+            let size = match (header >> 1) & 0x03 {
+                // RLE blocks store their repeated byte only once.
+                1 => 1,
+                3 => return Err(invalid("zstd block type is reserved")),
+                _ => (header >> 3) as i64,
+            };
+
+            reader.seek_relative(size)?;
+            if last {
+                break;
+            }
+        }
+
+        if checksum {
+            reader.seek_relative(4)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::temp_file;
+    use super::*;
+
+    /// A frame of `len` raw bytes, whose content size takes two bytes, and is
+    /// therefore recorded as 256 less than it is.
+    fn two_byte_frame(len: u16) -> Vec<u8> {
+        let mut frame = ZSTD_MAGIC.to_le_bytes().to_vec();
+        frame.push(0x60);
+        frame.extend_from_slice(&(len - 256).to_le_bytes());
+        frame.extend_from_slice(&(1 | u32::from(len) << 3).to_le_bytes()[..3]);
+        frame.extend(vec![0xAB; len as usize]);
+        frame
+    }
+
+    /// A frame of `len` repetitions of a byte, whose content size takes one byte.
+    fn one_byte_frame(len: u8) -> Vec<u8> {
+        let mut frame = ZSTD_MAGIC.to_le_bytes().to_vec();
+        frame.extend_from_slice(&[0x20, len]);
+        frame.extend_from_slice(&(1 | 1 << 1 | u32::from(len) << 3).to_le_bytes()[..3]);
+        frame.push(b'x');
+        frame
+    }
+
+    fn zstd_size_of(test: &str, data: &[u8]) -> io::Result<Option<u64>> {
+        zstd_size(&mut temp_file(test, data))
+    }
+
+    #[test]
+    fn zstd_content_sizes() {
+        let mut data = two_byte_frame(300);
+        // A skippable frame, such as those which hold metadata.
+        data.extend_from_slice(&0x184D_2A5Eu32.to_le_bytes());
+        data.extend_from_slice(&5u32.to_le_bytes());
+        data.extend_from_slice(b"skip!");
+        data.extend(one_byte_frame(10));
+
+        #[cfg(feature = "zstd")]
+        assert_eq!(::zstd::stream::decode_all(&data[..]).unwrap().len(), 310);
+
+        assert_eq!(zstd_size_of("fcs", &data).unwrap(), Some(310));
+        assert_eq!(zstd_size_of("fcs-min", &two_byte_frame(256)).unwrap(), Some(256));
+    }
+
+    #[test]
+    fn zstd_without_content_size() {
+        let mut data = one_byte_frame(10);
+        data.extend_from_slice(&ZSTD_MAGIC.to_le_bytes());
+        data.extend_from_slice(&[0x00, 0x00]);
+        assert_eq!(zstd_size_of("no-fcs", &data).unwrap(), None);
+    }
+
+    #[test]
+    fn zstd_invalid() {
+        let mut data = one_byte_frame(10);
+        data.extend_from_slice(b"not a frame");
+        assert!(zstd_size_of("invalid", &data).is_err());
+    }
+}
+
+#[cfg(all(test, feature = "xz"))]
+mod xz_tests {
+    use super::super::temp_file;
+    use super::*;
+    use std::io::Write;
+    use xz2::stream::{Check, MtStreamBuilder, Stream};
+    use xz2::write::XzEncoder;
+
+    fn data(len: usize) -> Vec<u8> { (0..len).map(|i| (i * 7 % 251) as u8).collect() }
+
+    /// Compresses the data as a single stream, which is split into blocks of
+    /// `block_size` bytes, if a block size is given.
+    fn xz(data: &[u8], block_size: Option<u64>) -> Vec<u8> {
+        let stream = match block_size {
+            Some(block_size) => MtStreamBuilder::new()
+                .threads(1)
+                .block_size(block_size)
+                .encoder()
+                .unwrap(),
+            None => Stream::new_easy_encoder(6, Check::Crc64).unwrap(),
+        };
+
+        let mut encoder = XzEncoder::new_stream(Vec::new(), stream);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn xz_size_of(test: &str, data: &[u8]) -> io::Result<u64> {
+        xz_size(&mut temp_file(test, data))
+    }
+
+    /// The number of blocks listed in the index of the last stream.
+    fn blocks(xz: &[u8]) -> u64 {
+        let footer = &xz[xz.len() - 12..];
+        let index_size = (u64::from(read_u32(&footer[4..8])) + 1) * 4;
+        let mut index = &xz[xz.len() - 12 - index_size as usize + 1..];
+        xz_varint(&mut index).unwrap()
+    }
+
+    #[test]
+    fn single_block() {
+        let compressed = xz(&data(100_000), None);
+        assert_eq!(blocks(&compressed), 1);
+        assert_eq!(xz_size_of("xz-single", &compressed).unwrap(), 100_000);
+    }
+
+    #[test]
+    fn several_blocks() {
+        let compressed = xz(&data(50_000), Some(4096));
+        assert_eq!(blocks(&compressed), 13);
+        assert_eq!(xz_size_of("xz-blocks", &compressed).unwrap(), 50_000);
+    }
+
+    #[test]
+    fn padded_streams() {
+        // Streams may be concatenated, with padding between and after them.
+        let mut compressed = xz(&data(3000), Some(1024));
+        compressed.extend_from_slice(&[0; 8]);
+        compressed.extend(xz(&data(5000), None));
+        compressed.extend_from_slice(&[0; 4]);
+
+        let mut decompressed = Vec::new();
+        Compression::Xz
+            .decoder(temp_file("xz-decode", &compressed))
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed.len(), 8000);
+        assert_eq!(xz_size_of("xz-padded", &compressed).unwrap(), 8000);
+
+        // Padding must be a multiple of four bytes.
+        compressed.truncate(compressed.len() - 1);
+        assert!(xz_size_of("xz-misaligned", &compressed).is_err());
+    }
+
+    #[test]
+    fn invalid_index() {
+        let compressed = xz(&data(10_000), Some(4096));
+        let footer = compressed.len() - 12;
+        let index_size = (read_u32(&compressed[footer + 4..]) as usize + 1) * 4;
+
+        let mut indicator = compressed.clone();
+        indicator[footer - index_size] = 1;
+        let why = xz_size_of("xz-indicator", &indicator).unwrap_err();
+        assert_eq!(why.to_string(), "xz index indicator not found");
+
+        let mut magic = compressed.clone();
+        magic[footer + 11] = b'Y';
+        let why = xz_size_of("xz-magic", &magic).unwrap_err();
+        assert_eq!(why.to_string(), "xz stream footer not found");
+
+        let mut oversized = compressed.clone();
+        oversized[footer + 4..footer + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+        let why = xz_size_of("xz-oversized", &oversized).unwrap_err();
+        assert_eq!(why.to_string(), "xz index is larger than the file");
+
+        // The index claims more records than it holds.
+        let mut records = compressed;
+        records[footer - index_size + 1] = 0x7F;
+        assert!(xz_size_of("xz-records", &records).is_err());
+    }
+
+    #[test]
+    fn varints() {
+        let mut data: &[u8] = &[0x05, 0x80, 0x01, 0xFF, 0x7F];
+        assert_eq!(xz_varint(&mut data).unwrap(), 5);
+        assert_eq!(xz_varint(&mut data).unwrap(), 128);
+        assert_eq!(xz_varint(&mut data).unwrap(), 0x3FFF);
+        assert!(data.is_empty());
+
+        assert!(xz_varint(&mut &[0x80][..]).is_err());
+        assert!(xz_varint(&mut &[0x80; 10][..]).is_err());
+    }
+
+    #[test]
+    fn unrecorded_sizes() {
+        // Neither gzip nor bzip2 record the size, which is left to be counted
+        // as the image is written.
+        let mut file = temp_file("xz-gzip", b"\x1F\x8B");
+        assert_eq!(Compression::Gzip.uncompressed_size(&mut file).unwrap(), None);
+        let mut file = temp_file("xz-bzip2", b"BZh9");
+        assert_eq!(Compression::Bzip2.uncompressed_size(&mut file).unwrap(), None);
+
+        let compressed = xz(&data(10_000), None);
+        let mut file = temp_file("xz-recorded", &compressed);
+        assert_eq!(Compression::Xz.uncompressed_size(&mut file).unwrap(), Some(10_000));
+    }
+}
@@ -0,0 +1,158 @@
+use super::{fill, qcow2, read_error, sparse, vhd, ImageError};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+
+/// The layout of the data within an image, once it has been decompressed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// A byte-for-byte copy of the disk.
+    Raw,
+    /// An Android sparse image, as generated by `img2simg`.
+    AndroidSparse,
+    /// A QEMU copy-on-write virtual disk, version 2 or 3.
+    Qcow2,
+    /// A fixed or dynamic Virtual Hard Disk.
+    Vhd,
+}
+
+impl Format {
+    /// Determines the format from the magic bytes at the start of the decompressed image.
+    ///
+    /// Fixed VHDs only have a footer, so they will be detected as raw images.
+    pub fn detect(header: &[u8]) -> Format {
+        if sparse::is_sparse(header) {
+            Format::AndroidSparse
+        } else if qcow2::is_qcow2(header) {
+            Format::Qcow2
+        } else if vhd::is_vhd(header) {
+            Format::Vhd
+        } else {
+            Format::Raw
+        }
+    }
+
+    /// Whether the format must be read out of order, which is impossible
+    /// while decompressing the image or reading it from an archive.
+    pub fn needs_seeking(self) -> bool {
+        match self {
+            Format::Raw | Format::AndroidSparse => false,
+            Format::Qcow2 | Format::Vhd => true,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Format::Raw => "raw",
+            Format::AndroidSparse => "Android sparse",
+            Format::Qcow2 => "qcow2",
+            Format::Vhd => "VHD",
+        })
+    }
+}
+
+/// Produces the data of an image in order, along with where on the disk it belongs.
+pub(crate) trait Source: Send {
+    /// Fills as much of `buffer` as possible with a contiguous region of the
+    /// image, returning the offset of that region and its length. Regions
+    /// that need not be written are skipped over, and a length of zero marks
+    /// the end of the image.
+    fn fill(&mut self, buffer: &mut [u8]) -> Result<(u64, usize), ImageError>;
+}
+
+/// Reads the first `size` bytes of the image as they are, or all of them if
+/// the size of the image is not known.
+pub(crate) struct RawSource {
+    reader: Box<dyn Read + Send>,
+    offset: u64,
+    size:   Option<u64>,
+}
+
+impl RawSource {
+    pub fn new(reader: Box<dyn Read + Send>, size: Option<u64>) -> RawSource {
+        RawSource {
+            reader,
+            offset: 0,
+            size,
+        }
+    }
+}
+
+impl Source for RawSource {
+    fn fill(&mut self, buffer: &mut [u8]) -> Result<(u64, usize), ImageError> {
+        let offset = self.offset;
+        let remaining = self.size.map_or(u64::MAX, |size| size.saturating_sub(offset));
+        if remaining == 0 {
+            return Ok((offset, 0));
+        }
+
+        let want = remaining.min(buffer.len() as u64) as usize;
+        let len = fill(&mut self.reader, &mut buffer[..want])
+            .map_err(|why| ImageError::ReadError { why })?;
+        if len == 0 && self.size.is_some() {
+            return Err(ImageError::Eof);
+        }
+
+        self.offset += len as u64;
+        Ok((offset, len))
+    }
+}
+
+/// Locates the clusters of a virtual disk within its image file.
+pub(crate) trait ClusterMap: Send {
+    fn cluster_size(&self) -> u64;
+
+    /// Returns the offset of the cluster's data within the file, or `None`
+    /// if the cluster has not been allocated, and so reads as zeroes.
+    fn locate(&mut self, file: &File, cluster: u64) -> Result<Option<u64>, ImageError>;
+}
+
+/// Reads a virtual disk as a raw disk, one cluster at a time.
+pub(crate) struct MappedSource<M> {
+    file:   File,
+    map:    M,
+    offset: u64,
+    size:   u64,
+}
+
+impl<M: ClusterMap> MappedSource<M> {
+    pub fn new(file: File, map: M, size: u64) -> MappedSource<M> {
+        MappedSource {
+            file,
+            map,
+            offset: 0,
+            size,
+        }
+    }
+}
+
+impl<M: ClusterMap> Source for MappedSource<M> {
+    fn fill(&mut self, buffer: &mut [u8]) -> Result<(u64, usize), ImageError> {
+        let start = self.offset;
+        let cluster_size = self.map.cluster_size();
+        let mut filled = 0;
+
+        while filled < buffer.len() && self.offset < self.size {
+            let within = self.offset % cluster_size;
+            let len = (cluster_size - within)
+                .min(self.size - self.offset)
+                .min((buffer.len() - filled) as u64) as usize;
+
+            let data = &mut buffer[filled..filled + len];
+            match self.map.locate(&self.file, self.offset / cluster_size)? {
+                Some(position) => self.file
+                    .read_exact_at(data, position + within)
+                    .map_err(read_error)?,
+                None => data.iter_mut().for_each(|byte| *byte = 0),
+            }
+
+            self.offset += len as u64;
+            filled += len;
+        }
+
+        Ok((start, filled))
+    }
+}
@@ -0,0 +1,385 @@
+mod compression;
+mod format;
+mod qcow2;
+mod sparse;
+mod vhd;
+mod zip;
+
+pub use self::compression::Compression;
+pub use self::format::Format;
+use self::format::{MappedSource, RawSource, Source};
+use self::qcow2::{Qcow2Header, Qcow2Map};
+use self::sparse::{SparseHeader, SparseSource};
+use self::vhd::VhdFooter;
+use self::zip::{ZipEntry, ZIP_MAGIC};
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, sync_channel};
+use std::thread::{self, JoinHandle};
+
+use super::stream::{aligned, Chunk, ImageStream, Message, BUFFER_ALIGN, BUFFER_SIZE, POOL_SIZE};
+
+#[rustfmt::skip]
+#[derive(Debug, Fail)]
+pub enum ImageError {
+    #[fail(display = "image could not be opened: {}", why)]
+    Open { why: io::Error },
+    #[fail(display = "unable to get image metadata: {}", why)]
+    Metadata { why: io::Error },
+    #[fail(display = "image was not a file")]
+    NotAFile,
+    #[fail(display = "image is {} compressed, but support for it was not enabled", compression)]
+    UnsupportedCompression { compression: Compression },
+    #[fail(display = "unable to determine the decompressed size of the image: {}", why)]
+    Size { why: io::Error },
+    #[fail(display = "image is a zip archive, but support for it was not enabled")]
+    UnsupportedArchive,
+    #[fail(display = "image is not a zip archive, so entry '{}' cannot be selected", entry)]
+    NotAnArchive { entry: String },
+    #[fail(display = "unable to read zip archive: {}", why)]
+    Archive { why: io::Error },
+    #[fail(display = "zip archive has no entry named '{}'", entry)]
+    EntryNotFound { entry: String },
+    #[fail(display = "zip archive does not contain a disk image")]
+    NoImageInArchive,
+    #[fail(display = "zip archive contains several images, choose one of {:?}", candidates)]
+    AmbiguousArchive { candidates: Vec<String> },
+    #[fail(display = "zip entry '{}' {}", entry, reason)]
+    UnsupportedEntry { entry: String, reason: &'static str },
+    #[fail(display = "Android sparse image {}", reason)]
+    Sparse { reason: &'static str },
+    #[fail(display = "{} image {}", format, reason)]
+    VirtualDisk { format: Format, reason: &'static str },
+    #[fail(display = "{} images cannot be read from a compressed file or archive", format)]
+    NotSeekable { format: Format },
+    #[fail(display = "unable to read image: {}", why)]
+    ReadError { why: io::Error },
+    #[fail(display = "reached EOF prematurely")]
+    Eof,
+}
+
+/// A simple wrapper around a `File` that ensures that the file is a file, and
+/// obtains the file's size ahead of time.
+///
+/// Images that are compressed are detected by their magic bytes, and will be
+/// decompressed on the fly as they are streamed. Zip archives are likewise
+/// detected, and the disk image within them is streamed out of the archive.
+/// Android sparse images are expanded as they are streamed, and the disks
+/// within qcow2 and VHD images are streamed as raw disks.
+pub struct Image {
+    path:        PathBuf,
+    file:        File,
+    compression: Compression,
+    entry:       Option<ZipEntry>,
+    format:      Format,
+    size:        Option<u64>,
+}
+
+impl Image {
+    /// Opens the file, detects its compression, and obtains the size of the
+    /// image once decompressed, then returns an `Image` structure that
+    /// contains the opened file and its size.
+    ///
+    /// If the file is a zip archive, it must contain exactly one disk image.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Image, ImageError> {
+        Image::open(path.as_ref(), None)
+    }
+
+    /// Opens the disk image named `entry` from within the zip archive at `path`.
+    pub fn with_entry<P: AsRef<Path>>(path: P, entry: &str) -> Result<Image, ImageError> {
+        Image::open(path.as_ref(), Some(entry))
+    }
+
+    fn open(path: &Path, entry: Option<&str>) -> Result<Image, ImageError> {
+        let mut file = File::open(path).map_err(|why| ImageError::Open { why })?;
+        let metadata = file.metadata()
+            .map_err(|why| ImageError::Metadata { why })?;
+        if !metadata.file_type().is_file() {
+            return Err(ImageError::NotAFile);
+        }
+
+        let mut header = [0; 16];
+        let read = fill(&mut file, &mut header).map_err(|why| ImageError::ReadError { why })?;
+        let header = &header[..read];
+
+        let (compression, entry) = if header.starts_with(ZIP_MAGIC) {
+            if !cfg!(feature = "zip") {
+                return Err(ImageError::UnsupportedArchive);
+            }
+
+            let entries = zip::entries(&mut file).map_err(|why| ImageError::Archive { why })?;
+            let entry = select_entry(entries, entry)?;
+            if let Some(reason) = entry.unsupported() {
+                return Err(ImageError::UnsupportedEntry {
+                    entry: entry.name,
+                    reason,
+                });
+            }
+
+            (Compression::None, Some(entry))
+        } else if let Some(entry) = entry {
+            return Err(ImageError::NotAnArchive {
+                entry: entry.into(),
+            });
+        } else {
+            let compression = Compression::detect(header);
+            if !compression.is_supported() {
+                return Err(ImageError::UnsupportedCompression { compression });
+            }
+
+            (compression, None)
+        };
+
+        // The format is only apparent once the image has been decompressed.
+        let mut header = [0; 512];
+        let read = file.seek(SeekFrom::Start(0))
+            .and_then(|_| file.try_clone())
+            .and_then(|file| decoder(file, compression, entry.as_ref()))
+            .and_then(|mut reader| fill(&mut reader, &mut header))
+            .map_err(|why| ImageError::ReadError { why })?;
+        let header = &header[..read];
+
+        let seekable = compression == Compression::None && entry.is_none();
+        let footer = if seekable { VhdFooter::read(&file)? } else { None };
+
+        let mut format = Format::detect(header);
+        if format == Format::Raw && footer.is_some() {
+            format = Format::Vhd;
+        } else if format.needs_seeking() && !seekable {
+            return Err(ImageError::NotSeekable { format });
+        }
+
+        let size = match format {
+            Format::AndroidSparse => Some(SparseHeader::parse(header)?.image_size()),
+            Format::Qcow2 => {
+                let header = Qcow2Header::parse(header)?;
+                Qcow2Map::new(&file, &header)?.check_clusters(&file)?;
+                Some(header.disk_size())
+            }
+            Format::Vhd => match footer {
+                Some(footer) => Some(footer.disk_size()),
+                None => {
+                    return Err(ImageError::VirtualDisk {
+                        format,
+                        reason: "is missing its footer",
+                    })
+                }
+            },
+            Format::Raw => match entry {
+                Some(ref entry) => Some(entry.size),
+                None => compression
+                    .uncompressed_size(&mut file)
+                    .map_err(|why| ImageError::Size { why })?,
+            },
+        };
+
+        file.seek(SeekFrom::Start(0))
+            .map_err(|why| ImageError::ReadError { why })?;
+
+        Ok(Image {
+            path: path.to_path_buf(),
+            file,
+            compression,
+            entry,
+            format,
+            size,
+        })
+    }
+
+    pub fn get_path(&self) -> &Path { &self.path }
+
+    /// Returns the compression that the image was found to be wrapped in.
+    pub fn get_compression(&self) -> Compression { self.compression }
+
+    /// Returns the name of the disk image within the zip archive, if the
+    /// image was opened from an archive.
+    pub fn get_entry(&self) -> Option<&str> { self.entry.as_ref().map(|e| e.name.as_str()) }
+
+    /// Returns the layout of the data within the decompressed image.
+    pub fn get_format(&self) -> Format { self.format }
+
+    /// Returns the size of the image once decompressed and expanded, in bytes,
+    /// if it is known before the image is read.
+    ///
+    /// Images compressed with gzip or bzip2 do not record their size, nor do
+    /// some zstd images, and so their size is only known once they are written.
+    pub fn get_size(&self) -> Option<u64> { self.size }
+
+    /// Spawns a thread which reads the image one chunk at a time, and hands
+    /// each chunk to every one of the `targets` streams that are returned.
+    ///
+    /// Only `POOL_SIZE` chunk buffers are ever allocated. The reader waits for
+    /// the slowest target to release a buffer before filling it again, so the
+    /// memory used stays fixed regardless of the size of the image.
+    pub fn stream(
+        self,
+        targets: usize,
+    ) -> (JoinHandle<Result<(), ImageError>>, Vec<ImageStream>) {
+        let size = self.size;
+        let mut senders = Vec::with_capacity(targets);
+        let mut streams = Vec::with_capacity(targets);
+        for _ in 0..targets {
+            let (sender, receiver) = channel();
+            senders.push(sender);
+            streams.push(ImageStream::new(receiver, size));
+        }
+
+        let handle = thread::spawn(move || {
+            let mut source = self.into_source()?;
+
+            let (pool_sender, pool) = sync_channel(POOL_SIZE);
+            for _ in 0..POOL_SIZE {
+                let _ = pool_sender.send(vec![0; BUFFER_SIZE + BUFFER_ALIGN]);
+            }
+
+            while !senders.is_empty() {
+                // Blocks until every target has released one of the buffers.
+                let mut data = pool.recv().expect("chunk pool closed");
+                let (offset, len) = source.fill(aligned(&mut data, BUFFER_ALIGN, BUFFER_SIZE))?;
+                if len == 0 {
+                    break;
+                }
+
+                let chunk = Chunk::new(offset, data, len, pool_sender.clone());
+                senders.retain(|sender| sender.send(Message::Chunk(chunk.clone())).is_ok());
+            }
+
+            for sender in senders {
+                let _ = sender.send(Message::End);
+            }
+
+            Ok(())
+        });
+
+        (handle, streams)
+    }
+
+    /// Opens the image for reading, expanding it into the data that is to be
+    /// written to the disk.
+    fn into_source(self) -> Result<Box<dyn Source>, ImageError> {
+        let Image {
+            file,
+            compression,
+            entry,
+            format,
+            size,
+            ..
+        } = self;
+
+        let decoded = |file| {
+            decoder(file, compression, entry.as_ref()).map_err(|why| ImageError::ReadError { why })
+        };
+
+        Ok(match format {
+            Format::Raw => Box::new(RawSource::new(decoded(file)?, size)),
+            Format::AndroidSparse => Box::new(SparseSource::new(decoded(file)?)?),
+            Format::Qcow2 => {
+                let mut header = [0; 512];
+                let read = fill(&mut &file, &mut header).map_err(|why| ImageError::ReadError { why })?;
+                let header = Qcow2Header::parse(&header[..read])?;
+                let map = Qcow2Map::new(&file, &header)?;
+                Box::new(MappedSource::new(file, map, header.disk_size()))
+            }
+            Format::Vhd => match VhdFooter::read(&file)? {
+                Some(footer) => footer.source(file)?,
+                None => return Err(ImageError::Eof),
+            },
+        })
+    }
+}
+
+/// Opens a reader over the decompressed image, from within the archive if need be.
+fn decoder(
+    file: File,
+    compression: Compression,
+    entry: Option<&ZipEntry>,
+) -> io::Result<Box<dyn Read + Send>> {
+    match entry {
+        Some(entry) => entry.reader(file),
+        None => compression.decoder(file),
+    }
+}
+
+/// Chooses the entry named `name`, or otherwise the only disk image in the archive.
+fn select_entry(entries: Vec<ZipEntry>, name: Option<&str>) -> Result<ZipEntry, ImageError> {
+    if let Some(name) = name {
+        return entries
+            .into_iter()
+            .find(|entry| entry.name == name || entry.name.rsplit('/').next() == Some(name))
+            .ok_or_else(|| ImageError::EntryNotFound { entry: name.into() });
+    }
+
+    let mut candidates = if entries.len() == 1 {
+        entries
+    } else {
+        entries.into_iter().filter(|e| is_disk_image(&e.name)).collect()
+    };
+
+    match candidates.len() {
+        0 => Err(ImageError::NoImageInArchive),
+        1 => Ok(candidates.remove(0)),
+        _ => Err(ImageError::AmbiguousArchive {
+            candidates: candidates.into_iter().map(|e| e.name).collect(),
+        }),
+    }
+}
+
+fn is_disk_image(name: &str) -> bool {
+    let name = name.to_lowercase();
+    [".img", ".iso", ".raw"].iter().any(|ext| name.ends_with(ext))
+}
+
+/// Reads until the buffer is full, or the end of the file has been reached.
+fn fill<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buffer.len() {
+        match reader.read(&mut buffer[total..]) {
+            Ok(0) => break,
+            Ok(count) => total += count,
+            Err(ref why) if why.kind() == io::ErrorKind::Interrupted => continue,
+            Err(why) => return Err(why),
+        }
+    }
+
+    Ok(total)
+}
+
+pub(crate) fn read_u16(data: &[u8]) -> u16 { u16::from(data[0]) | u16::from(data[1]) << 8 }
+
+pub(crate) fn read_u32(data: &[u8]) -> u32 {
+    u32::from(read_u16(data)) | u32::from(read_u16(&data[2..])) << 16
+}
+
+pub(crate) fn read_u64(data: &[u8]) -> u64 {
+    u64::from(read_u32(data)) | u64::from(read_u32(&data[4..])) << 32
+}
+
+pub(crate) fn invalid(what: &str) -> io::Error { io::Error::new(io::ErrorKind::InvalidData, what) }
+
+pub(crate) fn read_be_u32(data: &[u8]) -> u32 {
+    data[..4].iter().fold(0, |acc, &byte| acc << 8 | u32::from(byte))
+}
+
+pub(crate) fn read_be_u64(data: &[u8]) -> u64 {
+    u64::from(read_be_u32(data)) << 32 | u64::from(read_be_u32(&data[4..]))
+}
+
+/// Creates a file holding `data`, which is removed once the file is closed.
+#[cfg(test)]
+pub(crate) fn temp_file(name: &str, data: &[u8]) -> File {
+    let path = ::std::env::temp_dir().join(format!("popsicle-{}-{}", name, ::std::process::id()));
+    ::std::fs::write(&path, data).unwrap();
+    let file = File::open(&path).unwrap();
+    let _ = ::std::fs::remove_file(&path);
+    file
+}
+
+/// Reading past the end of the image is reported as such, rather than as an I/O error.
+pub(crate) fn read_error(why: io::Error) -> ImageError {
+    match why.kind() {
+        io::ErrorKind::UnexpectedEof => ImageError::Eof,
+        _ => ImageError::ReadError { why },
+    }
+}
@@ -0,0 +1,338 @@
+//! Reads the guest disk out of QEMU's qcow2 images.
+
+use super::format::{ClusterMap, Format};
+use super::{read_be_u32, read_be_u64, read_error, ImageError};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+const QCOW2_MAGIC: &[u8] = b"QFI\xFB";
+const V2_HEADER_SIZE: usize = 72;
+const V3_HEADER_SIZE: usize = 104;
+
+const INCOMPATIBLE_DIRTY: u64 = 1;
+const INCOMPATIBLE_CORRUPT: u64 = 1 << 1;
+const INCOMPATIBLE_COMPRESSION_TYPE: u64 = 1 << 3;
+
+const OFFSET_MASK: u64 = 0x00FF_FFFF_FFFF_FE00;
+const L2_COMPRESSED: u64 = 1 << 62;
+const L2_ZERO: u64 = 1;
+
+pub(crate) fn is_qcow2(header: &[u8]) -> bool { header.starts_with(QCOW2_MAGIC) }
+
+fn unsupported(reason: &'static str) -> ImageError {
+    ImageError::VirtualDisk {
+        format: Format::Qcow2,
+        reason,
+    }
+}
+
+/// The fields of the qcow2 header which are needed to read the guest disk.
+pub(crate) struct Qcow2Header {
+    cluster_bits:    u32,
+    size:            u64,
+    l1_size:         u64,
+    l1_table_offset: u64,
+}
+
+impl Qcow2Header {
+    pub fn parse(header: &[u8]) -> Result<Qcow2Header, ImageError> {
+        if header.len() < V2_HEADER_SIZE {
+            return Err(ImageError::Eof);
+        }
+
+        let version = read_be_u32(&header[4..]);
+        if version != 2 && version != 3 {
+            return Err(unsupported("has an unsupported version"));
+        } else if read_be_u64(&header[8..]) != 0 {
+            return Err(unsupported("has a backing file, which is not supported"));
+        } else if read_be_u32(&header[32..]) != 0 {
+            return Err(unsupported("is encrypted"));
+        }
+
+        if version == 3 {
+            if header.len() < V3_HEADER_SIZE {
+                return Err(ImageError::Eof);
+            }
+
+            // Dirty images only have stale refcounts, which are never read here.
+            let incompatible = read_be_u64(&header[72..]);
+            if incompatible & INCOMPATIBLE_CORRUPT != 0 {
+                return Err(unsupported("is marked as corrupt"));
+            } else if incompatible & !(INCOMPATIBLE_DIRTY | INCOMPATIBLE_COMPRESSION_TYPE) != 0 {
+                return Err(unsupported("uses features which are not supported"));
+            }
+        }
+
+        let header = Qcow2Header {
+            cluster_bits:    read_be_u32(&header[20..]),
+            size:            read_be_u64(&header[24..]),
+            l1_size:         u64::from(read_be_u32(&header[36..])),
+            l1_table_offset: read_be_u64(&header[40..]),
+        };
+
+        if header.cluster_bits < 9 || header.cluster_bits > 21 {
+            return Err(unsupported("has an invalid cluster size"));
+        }
+
+        // Each L2 table fills a cluster with 8-byte entries.
+        let per_l2_table = 1u64 << (2 * header.cluster_bits - 3);
+        if header.l1_size < header.size.div_ceil(per_l2_table) {
+            return Err(unsupported("has an L1 table which is too small for the disk"));
+        }
+
+        Ok(header)
+    }
+
+    /// The size of the guest disk.
+    pub fn disk_size(&self) -> u64 { self.size }
+}
+
+/// Follows the two-level table of a qcow2 image to each guest cluster.
+pub(crate) struct Qcow2Map {
+    cluster_bits: u32,
+    l1:           Vec<u64>,
+    /// The index into the L1 table of the L2 table which was last read.
+    l2_index:     Option<usize>,
+    l2:           Vec<u8>,
+}
+
+impl Qcow2Map {
+    pub fn new(file: &File, header: &Qcow2Header) -> Result<Qcow2Map, ImageError> {
+        // The L1 table is held in memory, so its size is checked against the
+        // file, rather than trusted to be sensible.
+        let len = file.metadata()
+            .map_err(|why| ImageError::Metadata { why })?
+            .len();
+        let l1_end = header.l1_table_offset.checked_add(header.l1_size * 8);
+        if l1_end.is_none_or(|end| end > len) {
+            return Err(unsupported("has an L1 table which extends beyond the end of the file"));
+        }
+
+        let mut l1 = vec![0; header.l1_size as usize * 8];
+        file.read_exact_at(&mut l1, header.l1_table_offset)
+            .map_err(read_error)?;
+
+        Ok(Qcow2Map {
+            cluster_bits: header.cluster_bits,
+            l1:           l1.chunks(8).map(read_be_u64).collect(),
+            l2_index:     None,
+            l2:           vec![0; 1 << header.cluster_bits],
+        })
+    }
+
+    /// Reads every L2 table, to ensure that the image can be read before
+    /// anything is written.
+    pub fn check_clusters(&mut self, file: &File) -> Result<(), ImageError> {
+        let entries = 1 << (self.cluster_bits - 3);
+        for index in 0..self.l1.len() as u64 {
+            for cluster in index * entries..(index + 1) * entries {
+                self.locate(file, cluster)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ClusterMap for Qcow2Map {
+    fn cluster_size(&self) -> u64 { 1 << self.cluster_bits }
+
+    fn locate(&mut self, file: &File, cluster: u64) -> Result<Option<u64>, ImageError> {
+        let entries = 1 << (self.cluster_bits - 3);
+        let l1_index = (cluster / entries) as usize;
+        let l2_offset = self.l1[l1_index] & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(None);
+        }
+
+        if self.l2_index != Some(l1_index) {
+            self.l2_index = None;
+            file.read_exact_at(&mut self.l2, l2_offset)
+                .map_err(read_error)?;
+            self.l2_index = Some(l1_index);
+        }
+
+        let at = (cluster % entries) as usize * 8;
+        let entry = read_be_u64(&self.l2[at..]);
+        if entry & L2_COMPRESSED != 0 {
+            return Err(unsupported("has compressed clusters, which are not supported"));
+        } else if entry & L2_ZERO != 0 {
+            return Ok(None);
+        }
+
+        match entry & OFFSET_MASK {
+            0 => Ok(None),
+            offset => Ok(Some(offset)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::format::{MappedSource, Source};
+    use super::super::temp_file;
+    use super::*;
+
+    const CLUSTER: usize = 512;
+    const SIZE: u64 = 2000;
+
+    fn put_u32(data: &mut [u8], at: usize, value: u32) {
+        data[at..at + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_u64(data: &mut [u8], at: usize, value: u64) {
+        data[at..at + 8].copy_from_slice(&value.to_be_bytes());
+    }
+
+    /// A version 3 image of a 2000 byte disk in 512 byte clusters, with its L1
+    /// table in the second cluster, and its only L2 table in the third. The
+    /// first guest cluster is allocated, the second is not, the third is
+    /// marked as zeroes, and the fourth is allocated and partly used.
+    fn image() -> Vec<u8> {
+        let mut image = vec![0; 6 * CLUSTER];
+        image[..4].copy_from_slice(QCOW2_MAGIC);
+        put_u32(&mut image, 4, 3);
+        put_u32(&mut image, 20, 9);
+        put_u64(&mut image, 24, SIZE);
+        put_u32(&mut image, 36, 1);
+        put_u64(&mut image, 40, CLUSTER as u64);
+        put_u32(&mut image, 96, 4);
+        put_u32(&mut image, 100, V3_HEADER_SIZE as u32);
+
+        // Tables and clusters which are not shared have their copied flag set.
+        let copied = 1 << 63;
+        put_u64(&mut image, CLUSTER, copied | (2 * CLUSTER as u64));
+        put_u64(&mut image, 2 * CLUSTER, copied | (3 * CLUSTER as u64));
+        put_u64(&mut image, 2 * CLUSTER + 16, copied | (4 * CLUSTER as u64) | L2_ZERO);
+        put_u64(&mut image, 2 * CLUSTER + 24, copied | (5 * CLUSTER as u64));
+
+        image[3 * CLUSTER..4 * CLUSTER].iter_mut().for_each(|byte| *byte = b'a');
+        image[4 * CLUSTER..5 * CLUSTER].iter_mut().for_each(|byte| *byte = b'z');
+        image[5 * CLUSTER..].iter_mut().for_each(|byte| *byte = b'd');
+        image
+    }
+
+    fn expected() -> Vec<u8> {
+        let mut disk = vec![b'a'; CLUSTER];
+        disk.extend(vec![0; 2 * CLUSTER]);
+        disk.extend(vec![b'd'; SIZE as usize - 3 * CLUSTER]);
+        disk
+    }
+
+    fn read_disk(name: &str, image: &[u8]) -> Result<Vec<u8>, ImageError> {
+        let file = temp_file(name, image);
+        let header = Qcow2Header::parse(image)?;
+        let mut map = Qcow2Map::new(&file, &header)?;
+        map.check_clusters(&file)?;
+
+        let mut source = MappedSource::new(file, map, header.disk_size());
+        let mut disk = Vec::new();
+        let mut buffer = [0; 700];
+        loop {
+            match source.fill(&mut buffer)? {
+                (_, 0) => return Ok(disk),
+                (offset, len) => {
+                    assert_eq!(offset, disk.len() as u64);
+                    disk.extend_from_slice(&buffer[..len]);
+                }
+            }
+        }
+    }
+
+    fn reason(result: Result<impl Sized, ImageError>) -> &'static str {
+        match result {
+            Err(ImageError::VirtualDisk { reason, .. }) => reason,
+            Err(why) => panic!("unexpected error: {}", why),
+            Ok(_) => panic!("invalid image was accepted"),
+        }
+    }
+
+    #[test]
+    fn clusters() {
+        let image = image();
+        assert!(is_qcow2(&image));
+        assert_eq!(Qcow2Header::parse(&image).unwrap().disk_size(), SIZE);
+        assert_eq!(read_disk("qcow2-clusters", &image).unwrap(), expected());
+
+        // Version 2 headers end before the feature bits.
+        let mut image = image;
+        put_u32(&mut image, 4, 2);
+        image[V2_HEADER_SIZE..V3_HEADER_SIZE].iter_mut().for_each(|byte| *byte = 0xFF);
+        assert_eq!(read_disk("qcow2-v2", &image).unwrap(), expected());
+    }
+
+    #[test]
+    fn unallocated_l2_table() {
+        let mut image = image();
+        put_u64(&mut image, CLUSTER, 0);
+        assert_eq!(read_disk("qcow2-unallocated", &image).unwrap(), vec![0; SIZE as usize]);
+    }
+
+    #[test]
+    fn compressed() {
+        let mut image = image();
+        put_u64(&mut image, 2 * CLUSTER + 24, L2_COMPRESSED | (5 * CLUSTER as u64));
+        let why = reason(read_disk("qcow2-compressed", &image));
+        assert_eq!(why, "has compressed clusters, which are not supported");
+    }
+
+    #[test]
+    fn unsupported_headers() {
+        let mut encrypted = image();
+        put_u32(&mut encrypted, 32, 1);
+        assert_eq!(reason(Qcow2Header::parse(&encrypted)), "is encrypted");
+
+        let mut backed = image();
+        put_u64(&mut backed, 8, 4 * CLUSTER as u64);
+        assert_eq!(
+            reason(Qcow2Header::parse(&backed)),
+            "has a backing file, which is not supported"
+        );
+
+        let mut corrupt = image();
+        put_u64(&mut corrupt, 72, INCOMPATIBLE_CORRUPT);
+        assert_eq!(reason(Qcow2Header::parse(&corrupt)), "is marked as corrupt");
+
+        let mut external = image();
+        put_u64(&mut external, 72, 1 << 2);
+        assert_eq!(
+            reason(Qcow2Header::parse(&external)),
+            "uses features which are not supported"
+        );
+
+        let mut version = image();
+        put_u32(&mut version, 4, 1);
+        assert_eq!(reason(Qcow2Header::parse(&version)), "has an unsupported version");
+
+        let mut clusters = image();
+        put_u32(&mut clusters, 20, 22);
+        assert_eq!(reason(Qcow2Header::parse(&clusters)), "has an invalid cluster size");
+
+        assert!(Qcow2Header::parse(&image()[..V2_HEADER_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn l1_table() {
+        let mut small = image();
+        put_u32(&mut small, 36, 0);
+        assert_eq!(
+            reason(Qcow2Header::parse(&small)),
+            "has an L1 table which is too small for the disk"
+        );
+
+        // A corrupt size would otherwise have gigabytes allocated for the table.
+        let mut huge = image();
+        put_u32(&mut huge, 36, u32::MAX);
+        assert_eq!(
+            reason(read_disk("qcow2-huge", &huge)),
+            "has an L1 table which extends beyond the end of the file"
+        );
+
+        let mut beyond = image();
+        put_u64(&mut beyond, 40, u64::MAX - 4);
+        assert_eq!(
+            reason(read_disk("qcow2-beyond", &beyond)),
+            "has an L1 table which extends beyond the end of the file"
+        );
+    }
+}
@@ -0,0 +1,392 @@
+//! Expands Android sparse images, as produced by `img2simg` and the AOSP build.
+
+use super::format::Source;
+use super::{read_error, read_u16, read_u32, ImageError};
+use crc32fast::Hasher;
+use std::io::{self, Read};
+
+const SPARSE_MAGIC: u32 = 0xED26_FF3A;
+const FILE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+
+const CHUNK_RAW: u16 = 0xCAC1;
+const CHUNK_FILL: u16 = 0xCAC2;
+const CHUNK_DONT_CARE: u16 = 0xCAC3;
+const CHUNK_CRC32: u16 = 0xCAC4;
+
+pub(crate) fn is_sparse(header: &[u8]) -> bool {
+    header.len() >= 4 && read_u32(header) == SPARSE_MAGIC
+}
+
+/// The file header of a sparse image.
+pub(crate) struct SparseHeader {
+    header_size:       usize,
+    chunk_header_size: usize,
+    block_size:        u64,
+    blocks:            u64,
+    chunks:            u32,
+    checksum:          u32,
+}
+
+impl SparseHeader {
+    pub fn parse(header: &[u8]) -> Result<SparseHeader, ImageError> {
+        if header.len() < FILE_HEADER_SIZE {
+            return Err(ImageError::Eof);
+        } else if !is_sparse(header) {
+            return Err(ImageError::Sparse {
+                reason: "is missing its magic number",
+            });
+        } else if read_u16(&header[4..]) != 1 {
+            return Err(ImageError::Sparse {
+                reason: "has an unsupported major version",
+            });
+        }
+
+        let header = SparseHeader {
+            header_size:       read_u16(&header[8..]) as usize,
+            chunk_header_size: read_u16(&header[10..]) as usize,
+            block_size:        u64::from(read_u32(&header[12..])),
+            blocks:            u64::from(read_u32(&header[16..])),
+            chunks:            read_u32(&header[20..]),
+            checksum:          read_u32(&header[24..]),
+        };
+
+        if header.header_size < FILE_HEADER_SIZE || header.chunk_header_size < CHUNK_HEADER_SIZE {
+            return Err(ImageError::Sparse {
+                reason: "has headers that are too small",
+            });
+        } else if header.block_size == 0 || !header.block_size.is_multiple_of(4) {
+            return Err(ImageError::Sparse {
+                reason: "has an invalid block size",
+            });
+        }
+
+        Ok(header)
+    }
+
+    /// The size of the image once it has been expanded.
+    pub fn image_size(&self) -> u64 { self.block_size * self.blocks }
+}
+
+/// What remains to be produced of the chunk currently being expanded.
+enum Pending {
+    None,
+    Raw(u64),
+    Fill([u8; 4], u64),
+    Skip(u64),
+}
+
+/// Expands the chunks of a sparse image as it is read, skipping over the
+/// regions that the image does not care about, and checking the CRC-32 of
+/// the expanded data wherever the image records one.
+pub(crate) struct SparseSource {
+    reader:  Box<dyn Read + Send>,
+    header:  SparseHeader,
+    chunks:  u32,
+    pending: Pending,
+    offset:  u64,
+    hasher:  Hasher,
+}
+
+impl SparseSource {
+    pub fn new(mut reader: Box<dyn Read + Send>) -> Result<SparseSource, ImageError> {
+        let mut header = [0; FILE_HEADER_SIZE];
+        read_exact(&mut reader, &mut header)?;
+        let header = SparseHeader::parse(&header)?;
+        skip(&mut reader, header.header_size - FILE_HEADER_SIZE)?;
+
+        Ok(SparseSource {
+            reader,
+            chunks: header.chunks,
+            header,
+            pending: Pending::None,
+            offset: 0,
+            hasher: Hasher::new(),
+        })
+    }
+
+    /// Reads the next chunk header, and any data that accompanies it.
+    fn next_chunk(&mut self) -> Result<(), ImageError> {
+        let mut header = [0; CHUNK_HEADER_SIZE];
+        read_exact(&mut self.reader, &mut header)?;
+        skip(&mut self.reader, self.header.chunk_header_size - CHUNK_HEADER_SIZE)?;
+        self.chunks -= 1;
+
+        let kind = read_u16(&header);
+        let size = u64::from(read_u32(&header[4..])) * self.header.block_size;
+        let data = u64::from(read_u32(&header[8..]))
+            .checked_sub(self.header.chunk_header_size as u64)
+            .ok_or(ImageError::Sparse {
+                reason: "has a chunk that is smaller than its header",
+            })?;
+
+        let expected = match kind {
+            CHUNK_RAW => size,
+            CHUNK_FILL | CHUNK_CRC32 => 4,
+            CHUNK_DONT_CARE => 0,
+            _ => {
+                return Err(ImageError::Sparse {
+                    reason: "contains a chunk of an unknown type",
+                })
+            }
+        };
+
+        if data != expected {
+            return Err(ImageError::Sparse {
+                reason: "has a chunk whose size does not match its type",
+            });
+        }
+
+        if self.offset + size > self.header.image_size() {
+            return Err(ImageError::Sparse {
+                reason: "has chunks which extend beyond the end of the image",
+            });
+        }
+
+        let mut value = [0; 4];
+        if expected == 4 {
+            read_exact(&mut self.reader, &mut value)?;
+        }
+
+        self.pending = match kind {
+            CHUNK_RAW => Pending::Raw(size),
+            CHUNK_FILL => Pending::Fill(value, size),
+            CHUNK_DONT_CARE => Pending::Skip(size),
+            _ => {
+                if self.hasher.clone().finalize() != read_u32(&value) {
+                    return Err(ImageError::Sparse {
+                        reason: "failed its CRC-32 check",
+                    });
+                }
+                Pending::None
+            }
+        };
+
+        Ok(())
+    }
+
+    /// Moves past `len` bytes of the pending chunk.
+    fn advance(&mut self, len: u64) {
+        self.offset += len;
+        self.pending = match self.pending {
+            Pending::Raw(left) if left > len => Pending::Raw(left - len),
+            Pending::Fill(value, left) if left > len => Pending::Fill(value, left - len),
+            _ => Pending::None,
+        };
+    }
+}
+
+impl Source for SparseSource {
+    fn fill(&mut self, buffer: &mut [u8]) -> Result<(u64, usize), ImageError> {
+        let mut start = self.offset;
+        let mut filled = 0;
+
+        while filled < buffer.len() {
+            let space = (buffer.len() - filled) as u64;
+            match self.pending {
+                Pending::None if self.chunks == 0 => break,
+                Pending::None => self.next_chunk()?,
+                Pending::Raw(remaining) => {
+                    let len = remaining.min(space) as usize;
+                    let data = &mut buffer[filled..filled + len];
+                    read_exact(&mut self.reader, data)?;
+                    self.hasher.update(data);
+                    self.advance(len as u64);
+                    filled += len;
+                }
+                Pending::Fill(value, remaining) => {
+                    let len = remaining.min(space) as usize;
+                    let data = &mut buffer[filled..filled + len];
+                    // Fill chunks are a whole number of blocks, which keeps the
+                    // pattern aligned to the offset within the image.
+                    for (pos, byte) in data.iter_mut().enumerate() {
+                        *byte = value[(self.offset as usize + pos) % 4];
+                    }
+                    self.hasher.update(data);
+                    self.advance(len as u64);
+                    filled += len;
+                }
+                // The data that is written must be contiguous, so a skipped
+                // region ends the current fill.
+                Pending::Skip(_) if filled != 0 => break,
+                Pending::Skip(remaining) => {
+                    // Skipped regions count as zeroes in the checksum.
+                    let zeroes = [0; 4096];
+                    let mut left = remaining;
+                    while left != 0 {
+                        let len = left.min(zeroes.len() as u64);
+                        self.hasher.update(&zeroes[..len as usize]);
+                        left -= len;
+                    }
+                    self.advance(remaining);
+                    start = self.offset;
+                }
+            }
+        }
+
+        let finished = filled == 0 && self.header.checksum != 0;
+        if finished && self.hasher.clone().finalize() != self.header.checksum {
+            return Err(ImageError::Sparse {
+                reason: "failed its CRC-32 check",
+            });
+        }
+
+        Ok((start, filled))
+    }
+}
+
+fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<(), ImageError> {
+    reader.read_exact(buffer).map_err(read_error)
+}
+
+fn skip<R: Read>(reader: &mut R, len: usize) -> Result<(), ImageError> {
+    let copied = io::copy(&mut reader.take(len as u64), &mut io::sink())
+        .map_err(|why| ImageError::ReadError { why })?;
+    if copied != len as u64 {
+        return Err(ImageError::Eof);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const BLOCK: u32 = 8;
+
+    fn chunk(image: &mut Vec<u8>, kind: u16, blocks: u32, data: &[u8]) {
+        image.extend_from_slice(&kind.to_le_bytes());
+        image.extend_from_slice(&[0, 0]);
+        image.extend_from_slice(&blocks.to_le_bytes());
+        image.extend_from_slice(&(CHUNK_HEADER_SIZE as u32 + data.len() as u32).to_le_bytes());
+        image.extend_from_slice(data);
+    }
+
+    /// The image of five 8-byte blocks that `sparse()` describes, once expanded.
+    fn expanded() -> Vec<u8> {
+        let mut image = b"raw data".to_vec();
+        image.extend_from_slice(&[1, 2, 3, 4].repeat(4));
+        image.extend_from_slice(&[0; 8]);
+        image.extend_from_slice(b"the end!");
+        image
+    }
+
+    /// A sparse image holding a block of raw data, two filled blocks, a block
+    /// which is not cared about, the CRC-32 of all of those, and another block
+    /// of raw data.
+    fn sparse(crc: u32, checksum: u32) -> Vec<u8> {
+        let mut image = Vec::new();
+        image.extend_from_slice(&SPARSE_MAGIC.to_le_bytes());
+        image.extend_from_slice(&[1, 0, 0, 0]);
+        image.extend_from_slice(&(FILE_HEADER_SIZE as u16).to_le_bytes());
+        image.extend_from_slice(&(CHUNK_HEADER_SIZE as u16).to_le_bytes());
+        image.extend_from_slice(&BLOCK.to_le_bytes());
+        image.extend_from_slice(&5u32.to_le_bytes());
+        image.extend_from_slice(&5u32.to_le_bytes());
+        image.extend_from_slice(&checksum.to_le_bytes());
+
+        chunk(&mut image, CHUNK_RAW, 1, b"raw data");
+        chunk(&mut image, CHUNK_FILL, 2, &[1, 2, 3, 4]);
+        chunk(&mut image, CHUNK_DONT_CARE, 1, &[]);
+        chunk(&mut image, CHUNK_CRC32, 0, &crc.to_le_bytes());
+        chunk(&mut image, CHUNK_RAW, 1, b"the end!");
+        image
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut hasher = Hasher::new();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    fn open(image: Vec<u8>) -> SparseSource {
+        SparseSource::new(Box::new(Cursor::new(image))).unwrap()
+    }
+
+    /// Fills buffers of `size` until the image ends, placing each region where it belongs.
+    fn expand(source: &mut SparseSource, size: usize) -> Result<Vec<u8>, ImageError> {
+        let mut image = vec![0; source.header.image_size() as usize];
+        let mut buffer = vec![0; size];
+        loop {
+            match source.fill(&mut buffer)? {
+                (_, 0) => return Ok(image),
+                (offset, len) => {
+                    let offset = offset as usize;
+                    image[offset..offset + len].copy_from_slice(&buffer[..len]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn chunks() {
+        let image = expanded();
+        let mut source = open(sparse(crc32(&image[..32]), crc32(&image)));
+        assert_eq!(source.header.image_size(), 40);
+
+        // Regions end where a region which is not cared about begins.
+        let mut buffer = [0xFF; 64];
+        assert_eq!(source.fill(&mut buffer).unwrap(), (0, 24));
+        assert_eq!(&buffer[..24], &image[..24]);
+        assert_eq!(source.fill(&mut buffer).unwrap(), (32, 8));
+        assert_eq!(&buffer[..8], b"the end!");
+        assert_eq!(source.fill(&mut buffer).unwrap(), (40, 0));
+    }
+
+    #[test]
+    fn small_buffers() {
+        let image = expanded();
+        for size in 1..10 {
+            let mut source = open(sparse(crc32(&image[..32]), crc32(&image)));
+            assert_eq!(expand(&mut source, size).unwrap(), image);
+        }
+    }
+
+    #[test]
+    fn unchecked() {
+        // Images need not record the checksum of the whole image.
+        let image = expanded();
+        let mut source = open(sparse(crc32(&image[..32]), 0));
+        assert_eq!(expand(&mut source, 64).unwrap(), image);
+    }
+
+    #[test]
+    fn corrupt() {
+        let image = expanded();
+        let mut source = open(sparse(crc32(&image[..24]), crc32(&image)));
+        match expand(&mut source, 64) {
+            Err(ImageError::Sparse { reason }) => assert_eq!(reason, "failed its CRC-32 check"),
+            other => panic!("corrupt chunk was accepted: {:?}", other),
+        }
+
+        let mut source = open(sparse(crc32(&image[..32]), crc32(&image[..32])));
+        match expand(&mut source, 64) {
+            Err(ImageError::Sparse { reason }) => assert_eq!(reason, "failed its CRC-32 check"),
+            other => panic!("corrupt image was accepted: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let mut image = sparse(0, 0);
+        image[4] = 2;
+        assert!(SparseSource::new(Box::new(Cursor::new(image))).is_err());
+
+        let mut image = sparse(0, 0);
+        image[12] = 6;
+        assert!(SparseSource::new(Box::new(Cursor::new(image))).is_err());
+
+        // The raw chunk claims a block more than it holds.
+        let mut image = sparse(0, 0);
+        image[FILE_HEADER_SIZE + 4] = 2;
+        let mut source = open(image);
+        match source.fill(&mut [0; 64]) {
+            Err(ImageError::Sparse { reason }) => {
+                assert_eq!(reason, "has a chunk whose size does not match its type")
+            }
+            other => panic!("invalid chunk was accepted: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,335 @@
+//! Reads the disk out of fixed and dynamic Virtual Hard Disk images.
+
+use super::format::{ClusterMap, Format, MappedSource, RawSource, Source};
+use super::{read_be_u32, read_be_u64, read_error, ImageError};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+const FOOTER_COOKIE: &[u8] = b"conectix";
+const DYNAMIC_COOKIE: &[u8] = b"cxsparse";
+const FOOTER_SIZE: u64 = 512;
+const DYNAMIC_HEADER_SIZE: usize = 1024;
+const SECTOR_SIZE: u64 = 512;
+
+const DISK_FIXED: u32 = 2;
+const DISK_DYNAMIC: u32 = 3;
+const DISK_DIFFERENCING: u32 = 4;
+
+const UNALLOCATED: u32 = 0xFFFF_FFFF;
+
+/// Dynamic VHDs begin with a copy of their footer.
+pub(crate) fn is_vhd(header: &[u8]) -> bool { header.starts_with(FOOTER_COOKIE) }
+
+fn unsupported(reason: &'static str) -> ImageError {
+    ImageError::VirtualDisk {
+        format: Format::Vhd,
+        reason,
+    }
+}
+
+/// VHD checksums are the one's complement of the sum of every other byte.
+fn checksum(data: &[u8], field: usize) -> u32 {
+    let sum = data.iter()
+        .enumerate()
+        .filter(|&(pos, _)| pos < field || pos >= field + 4)
+        .fold(0u32, |sum, (_, &byte)| sum.wrapping_add(u32::from(byte)));
+    !sum
+}
+
+/// The footer at the end of every VHD.
+pub(crate) struct VhdFooter {
+    size:        u64,
+    /// The offset of the dynamic disk header, if the disk is dynamic.
+    data_offset: Option<u64>,
+}
+
+impl VhdFooter {
+    /// Reads the footer from the end of the file, if the file has one.
+    pub fn read(file: &File) -> Result<Option<VhdFooter>, ImageError> {
+        let len = file.metadata()
+            .map_err(|why| ImageError::Metadata { why })?
+            .len();
+        if len < FOOTER_SIZE {
+            return Ok(None);
+        }
+
+        let mut footer = [0; FOOTER_SIZE as usize];
+        file.read_exact_at(&mut footer, len - FOOTER_SIZE)
+            .map_err(read_error)?;
+        if !footer.starts_with(FOOTER_COOKIE) {
+            return Ok(None);
+        }
+
+        if checksum(&footer, 64) != read_be_u32(&footer[64..]) {
+            return Err(unsupported("footer does not match its checksum"));
+        }
+
+        let size = read_be_u64(&footer[48..]);
+        let data_offset = match read_be_u32(&footer[60..]) {
+            DISK_FIXED => {
+                if len - FOOTER_SIZE < size {
+                    return Err(unsupported("is smaller than the disk it contains"));
+                }
+                None
+            }
+            DISK_DYNAMIC => Some(read_be_u64(&footer[16..])),
+            DISK_DIFFERENCING => {
+                return Err(unsupported("is a differencing disk, which is not supported"))
+            }
+            _ => return Err(unsupported("has an unknown disk type")),
+        };
+
+        Ok(Some(VhdFooter { size, data_offset }))
+    }
+
+    /// The size of the disk that the VHD contains.
+    pub fn disk_size(&self) -> u64 { self.size }
+
+    /// Reads the disk within the VHD, which for fixed disks is stored as is.
+    pub fn source(&self, file: File) -> Result<Box<dyn Source>, ImageError> {
+        Ok(match self.data_offset {
+            Some(offset) => {
+                let map = VhdMap::new(&file, offset, self.size)?;
+                Box::new(MappedSource::new(file, map, self.size))
+            }
+            None => Box::new(RawSource::new(Box::new(file), Some(self.size))),
+        })
+    }
+}
+
+/// Locates the blocks of a dynamic VHD through its block allocation table.
+pub(crate) struct VhdMap {
+    block_size:  u64,
+    /// Each block is preceded by a bitmap of its sectors, padded to a sector.
+    bitmap_size: u64,
+    table:       Vec<u32>,
+}
+
+impl VhdMap {
+    pub fn new(file: &File, offset: u64, size: u64) -> Result<VhdMap, ImageError> {
+        let mut header = [0; DYNAMIC_HEADER_SIZE];
+        file.read_exact_at(&mut header, offset)
+            .map_err(read_error)?;
+        if !header.starts_with(DYNAMIC_COOKIE) {
+            return Err(unsupported("dynamic disk header not found"));
+        } else if checksum(&header, 36) != read_be_u32(&header[36..]) {
+            return Err(unsupported("dynamic disk header does not match its checksum"));
+        }
+
+        let table_offset = read_be_u64(&header[16..]);
+        let entries = u64::from(read_be_u32(&header[28..]));
+        let block_size = u64::from(read_be_u32(&header[32..]));
+        if block_size < SECTOR_SIZE || !block_size.is_power_of_two() {
+            return Err(unsupported("has an invalid block size"));
+        } else if entries * block_size < size {
+            return Err(unsupported("has a block table which is too small for the disk"));
+        }
+
+        // The table is held in memory, so its size is checked against the file.
+        let len = file.metadata()
+            .map_err(|why| ImageError::Metadata { why })?
+            .len();
+        if table_offset.checked_add(entries * 4).is_none_or(|end| end > len) {
+            return Err(unsupported("has a block table which extends beyond the end of the file"));
+        }
+
+        let mut table = vec![0; entries as usize * 4];
+        file.read_exact_at(&mut table, table_offset)
+            .map_err(read_error)?;
+
+        let bitmap_size = (block_size / SECTOR_SIZE / 8).div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+
+        Ok(VhdMap {
+            block_size,
+            bitmap_size,
+            table: table.chunks(4).map(read_be_u32).collect(),
+        })
+    }
+}
+
+impl ClusterMap for VhdMap {
+    fn cluster_size(&self) -> u64 { self.block_size }
+
+    fn locate(&mut self, _file: &File, cluster: u64) -> Result<Option<u64>, ImageError> {
+        match self.table[cluster as usize] {
+            UNALLOCATED => Ok(None),
+            sector => Ok(Some(u64::from(sector) * SECTOR_SIZE + self.bitmap_size)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::temp_file;
+    use super::*;
+
+    const BLOCK: usize = 4096;
+    const SIZE: u64 = 3 * BLOCK as u64;
+
+    fn put_u32(data: &mut [u8], at: usize, value: u32) {
+        data[at..at + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_u64(data: &mut [u8], at: usize, value: u64) {
+        data[at..at + 8].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn footer(disk_type: u32, data_offset: u64) -> Vec<u8> {
+        let mut footer = vec![0; FOOTER_SIZE as usize];
+        footer[..8].copy_from_slice(FOOTER_COOKIE);
+        put_u32(&mut footer, 8, 2);
+        put_u32(&mut footer, 12, 0x0001_0000);
+        put_u64(&mut footer, 16, data_offset);
+        put_u64(&mut footer, 40, SIZE);
+        put_u64(&mut footer, 48, SIZE);
+        put_u32(&mut footer, 60, disk_type);
+        let sum = checksum(&footer, 64);
+        put_u32(&mut footer, 64, sum);
+        footer
+    }
+
+    /// A fixed disk holding `disk`, followed by its footer.
+    fn fixed(disk: &[u8]) -> Vec<u8> {
+        let mut image = disk.to_vec();
+        image.extend(footer(DISK_FIXED, u64::MAX));
+        image
+    }
+
+    /// A dynamic disk of three 4 KiB blocks, of which the first and last are
+    /// allocated. The copy of the footer is followed by the dynamic disk
+    /// header, the block allocation table, and then each block, which is
+    /// preceded by the bitmap of its sectors.
+    fn dynamic() -> Vec<u8> {
+        let mut image = footer(DISK_DYNAMIC, 512);
+
+        let mut header = vec![0; DYNAMIC_HEADER_SIZE];
+        header[..8].copy_from_slice(DYNAMIC_COOKIE);
+        put_u64(&mut header, 8, u64::MAX);
+        put_u64(&mut header, 16, 1536);
+        put_u32(&mut header, 24, 0x0001_0000);
+        put_u32(&mut header, 28, 3);
+        put_u32(&mut header, 32, BLOCK as u32);
+        let sum = checksum(&header, 36);
+        put_u32(&mut header, 36, sum);
+        image.extend(header);
+
+        let mut table = vec![0xFF; 512];
+        put_u32(&mut table, 0, 4);
+        put_u32(&mut table, 8, 13);
+        image.extend(table);
+
+        for &byte in b"ac" {
+            image.extend(vec![0xFF; 512]);
+            image.extend(vec![byte; BLOCK]);
+        }
+
+        image.extend(footer(DISK_DYNAMIC, 512));
+        image
+    }
+
+    fn read_disk(name: &str, image: &[u8]) -> Result<Vec<u8>, ImageError> {
+        let file = temp_file(name, image);
+        let footer = VhdFooter::read(&file)?.expect("footer not found");
+        let size = footer.disk_size();
+        let mut source = footer.source(file)?;
+
+        let mut disk = Vec::new();
+        let mut buffer = [0; 3000];
+        loop {
+            match source.fill(&mut buffer)? {
+                (_, 0) => {
+                    assert_eq!(disk.len() as u64, size);
+                    return Ok(disk);
+                }
+                (offset, len) => {
+                    assert_eq!(offset, disk.len() as u64);
+                    disk.extend_from_slice(&buffer[..len]);
+                }
+            }
+        }
+    }
+
+    fn reason(result: Result<impl Sized, ImageError>) -> &'static str {
+        match result {
+            Err(ImageError::VirtualDisk { reason, .. }) => reason,
+            Err(why) => panic!("unexpected error: {}", why),
+            Ok(_) => panic!("invalid image was accepted"),
+        }
+    }
+
+    #[test]
+    fn fixed_disk() {
+        let disk: Vec<u8> = (0..SIZE).map(|byte| byte as u8).collect();
+        let image = fixed(&disk);
+        assert!(!is_vhd(&image));
+        assert_eq!(read_disk("vhd-fixed", &image).unwrap(), disk);
+
+        let why = VhdFooter::read(&temp_file("vhd-short", &fixed(&disk[..BLOCK])));
+        assert_eq!(reason(why), "is smaller than the disk it contains");
+    }
+
+    #[test]
+    fn dynamic_disk() {
+        let image = dynamic();
+        assert!(is_vhd(&image));
+
+        // The bitmap before each block is skipped, and unallocated blocks are zeroes.
+        let mut disk = vec![b'a'; BLOCK];
+        disk.extend(vec![0; BLOCK]);
+        disk.extend(vec![b'c'; BLOCK]);
+        assert_eq!(read_disk("vhd-dynamic", &image).unwrap(), disk);
+    }
+
+    #[test]
+    fn footers() {
+        assert!(VhdFooter::read(&temp_file("vhd-raw", &[0; 4096])).unwrap().is_none());
+        assert!(VhdFooter::read(&temp_file("vhd-tiny", b"conectix")).unwrap().is_none());
+
+        let mut image = fixed(&[0; SIZE as usize]);
+        let at = image.len() - 512 + 48;
+        image[at] ^= 1;
+        let why = VhdFooter::read(&temp_file("vhd-checksum", &image));
+        assert_eq!(reason(why), "footer does not match its checksum");
+
+        let mut image = vec![0; SIZE as usize];
+        image.extend(footer(DISK_DIFFERENCING, 512));
+        let why = VhdFooter::read(&temp_file("vhd-differencing", &image));
+        assert_eq!(reason(why), "is a differencing disk, which is not supported");
+
+        let mut image = vec![0; SIZE as usize];
+        image.extend(footer(5, 512));
+        let why = VhdFooter::read(&temp_file("vhd-unknown", &image));
+        assert_eq!(reason(why), "has an unknown disk type");
+    }
+
+    #[test]
+    fn dynamic_headers() {
+        let mut image = dynamic();
+        image[512 + 40] ^= 1;
+        assert_eq!(
+            reason(read_disk("vhd-header-checksum", &image)),
+            "dynamic disk header does not match its checksum"
+        );
+
+        let with_header = |field: usize, value: u32| {
+            let mut image = dynamic();
+            put_u32(&mut image, 512 + field, value);
+            let sum = checksum(&image[512..512 + DYNAMIC_HEADER_SIZE], 36);
+            put_u32(&mut image, 512 + 36, sum);
+            image
+        };
+
+        assert_eq!(
+            reason(read_disk("vhd-block-size", &with_header(32, 3000))),
+            "has an invalid block size"
+        );
+        assert_eq!(
+            reason(read_disk("vhd-entries", &with_header(28, 2))),
+            "has a block table which is too small for the disk"
+        );
+        assert_eq!(
+            reason(read_disk("vhd-huge", &with_header(28, u32::MAX))),
+            "has a block table which extends beyond the end of the file"
+        );
+    }
+}
@@ -0,0 +1,471 @@
+use super::{invalid, read_u16, read_u32, read_u64};
+use crc32fast::Hasher;
+use std::io::{self, Read, Seek, SeekFrom};
+
+#[cfg(feature = "zip")]
+use flate2::read::DeflateDecoder;
+
+pub(crate) const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4B50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x0606_4B50;
+const ZIP64_LOCATOR: u32 = 0x0706_4B50;
+const CENTRAL_FILE_HEADER: u32 = 0x0201_4B50;
+const LOCAL_FILE_HEADER: u32 = 0x0403_4B50;
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+/// A file within a zip archive, as described by the archive's central directory.
+#[derive(Debug)]
+pub(crate) struct ZipEntry {
+    pub name:        String,
+    pub size:        u64,
+    method:          u16,
+    encrypted:       bool,
+    crc32:           u32,
+    compressed_size: u64,
+    header_offset:   u64,
+}
+
+impl ZipEntry {
+    /// Explains why the entry cannot be read, if it cannot be.
+    pub fn unsupported(&self) -> Option<&'static str> {
+        if self.encrypted {
+            Some("is encrypted")
+        } else if self.method != STORED && self.method != DEFLATED {
+            Some("uses an unsupported compression method")
+        } else {
+            None
+        }
+    }
+
+    /// Positions the file at the start of the entry's data, and returns a
+    /// reader that decompresses and checks the entry as it is read.
+    pub fn reader<R>(&self, mut file: R) -> io::Result<Box<dyn Read + Send>>
+    where
+        R: Read + Seek + Send + 'static,
+    {
+        let mut header = [0; 30];
+        file.seek(SeekFrom::Start(self.header_offset))?;
+        file.read_exact(&mut header)?;
+        if read_u32(&header) != LOCAL_FILE_HEADER {
+            return Err(invalid("zip local file header not found"));
+        }
+
+        let skip = u64::from(read_u16(&header[26..])) + u64::from(read_u16(&header[28..]));
+        file.seek(SeekFrom::Current(skip as i64))?;
+
+        let data = file.take(self.compressed_size);
+        let reader: Box<dyn Read + Send> = match self.method {
+            #[cfg(feature = "zip")]
+            DEFLATED => Box::new(DeflateDecoder::new(data)),
+            STORED => Box::new(data),
+            _ => return Err(invalid("zip entry uses an unsupported compression method")),
+        };
+
+        Ok(Box::new(CrcReader {
+            reader,
+            hasher: Hasher::new(),
+            expected: self.crc32,
+            remaining: self.size,
+        }))
+    }
+}
+
+/// Reads the central directory of a zip archive, returning every file in it.
+pub(crate) fn entries<R: Read + Seek>(file: &mut R) -> io::Result<Vec<ZipEntry>> {
+    let len = file.seek(SeekFrom::End(0))?;
+
+    // The end of central directory record is followed by a comment of up to
+    // 64 KiB, so it must be searched for from the end of the file.
+    let tail_len = len.min(22 + 0xFFFF);
+    let mut tail = vec![0; tail_len as usize];
+    file.seek(SeekFrom::Start(len - tail_len))?;
+    file.read_exact(&mut tail)?;
+
+    let eocd = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&pos| read_u32(&tail[pos..]) == END_OF_CENTRAL_DIRECTORY)
+        .ok_or_else(|| invalid("zip end of central directory not found"))?;
+
+    let record = &tail[eocd..];
+    let mut count = u64::from(read_u16(&record[10..]));
+    let mut directory_size = u64::from(read_u32(&record[12..]));
+    let mut directory_offset = u64::from(read_u32(&record[16..]));
+
+    if count == 0xFFFF || directory_size == 0xFFFF_FFFF || directory_offset == 0xFFFF_FFFF {
+        if eocd < 20 || read_u32(&tail[eocd - 20..]) != ZIP64_LOCATOR {
+            return Err(invalid("zip64 end of central directory locator not found"));
+        }
+
+        let mut record = [0; 56];
+        file.seek(SeekFrom::Start(read_u64(&tail[eocd - 12..])))?;
+        file.read_exact(&mut record)?;
+        if read_u32(&record) != ZIP64_END_OF_CENTRAL_DIRECTORY {
+            return Err(invalid("zip64 end of central directory not found"));
+        }
+
+        count = read_u64(&record[32..]);
+        directory_size = read_u64(&record[40..]);
+        directory_offset = read_u64(&record[48..]);
+    }
+
+    let mut directory = vec![0; directory_size as usize];
+    file.seek(SeekFrom::Start(directory_offset))?;
+    file.read_exact(&mut directory)?;
+
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    for _ in 0..count {
+        let header = directory
+            .get(pos..pos + 46)
+            .ok_or_else(|| invalid("zip central directory is truncated"))?;
+        if read_u32(header) != CENTRAL_FILE_HEADER {
+            return Err(invalid("zip central directory entry not found"));
+        }
+
+        let name_len = read_u16(&header[28..]) as usize;
+        let extra_len = read_u16(&header[30..]) as usize;
+        let comment_len = read_u16(&header[32..]) as usize;
+        let fields = directory
+            .get(pos + 46..pos + 46 + name_len + extra_len)
+            .ok_or_else(|| invalid("zip central directory is truncated"))?;
+        let (name, extra) = fields.split_at(name_len);
+
+        let mut entry = ZipEntry {
+            name:            String::from_utf8_lossy(name).into_owned(),
+            size:            u64::from(read_u32(&header[24..])),
+            method:          read_u16(&header[10..]),
+            encrypted:       read_u16(&header[8..]) & 1 != 0,
+            crc32:           read_u32(&header[16..]),
+            compressed_size: u64::from(read_u32(&header[20..])),
+            header_offset:   u64::from(read_u32(&header[42..])),
+        };
+
+        read_zip64_extra(&mut entry, extra);
+
+        if !entry.name.ends_with('/') {
+            entries.push(entry);
+        }
+
+        pos += 46 + name_len + extra_len + comment_len;
+    }
+
+    Ok(entries)
+}
+
+/// Fields which overflowed in the central directory are stored as 64-bit
+/// values within the zip64 extra field, in a fixed order.
+fn read_zip64_extra(entry: &mut ZipEntry, mut extra: &[u8]) {
+    while extra.len() >= 4 {
+        let id = read_u16(extra);
+        let len = (read_u16(&extra[2..]) as usize).min(extra.len() - 4);
+        if id == ZIP64_EXTRA_FIELD {
+            let mut values = extra[4..4 + len].chunks(8).filter(|v| v.len() == 8).map(read_u64);
+            for field in &mut [
+                &mut entry.size,
+                &mut entry.compressed_size,
+                &mut entry.header_offset,
+            ] {
+                if **field == 0xFFFF_FFFF {
+                    match values.next() {
+                        Some(value) => **field = value,
+                        None => break,
+                    }
+                }
+            }
+        }
+
+        extra = &extra[4 + len..];
+    }
+}
+
+/// Ensures that the data read matches the CRC-32 from the central directory,
+/// once the last byte of the entry has been read.
+struct CrcReader {
+    reader:    Box<dyn Read + Send>,
+    hasher:    Hasher,
+    expected:  u32,
+    remaining: u64,
+}
+
+impl Read for CrcReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.remaining = self.remaining.saturating_sub(read as u64);
+        if read != 0 && self.remaining == 0 && self.hasher.clone().finalize() != self.expected {
+            return Err(invalid("zip entry failed its CRC-32 check"));
+        }
+
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{select_entry, ImageError};
+    use super::*;
+    use std::io::Cursor;
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut hasher = Hasher::new();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    /// Builds an archive in memory, one entry at a time.
+    #[derive(Default)]
+    struct Archive {
+        data:      Vec<u8>,
+        directory: Vec<u8>,
+        count:     u16,
+    }
+
+    impl Archive {
+        /// Adds an entry holding `stored`, which decompresses to `data`. Entries
+        /// in zip64 form record their sizes and offset in the zip64 extra field.
+        fn add(&mut self, name: &str, method: u16, data: &[u8], stored: &[u8], zip64: bool) {
+            self.add_with_crc(name, method, data.len(), stored, crc32(data), zip64);
+        }
+
+        fn add_with_crc(
+            &mut self,
+            name: &str,
+            method: u16,
+            size: usize,
+            stored: &[u8],
+            crc: u32,
+            zip64: bool,
+        ) {
+            let offset = self.data.len() as u32;
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&[0; 4]);
+            fields.extend_from_slice(&crc.to_le_bytes());
+
+            let mut extra = Vec::new();
+            if zip64 {
+                fields.extend_from_slice(&[0xFF; 8]);
+                extra.extend_from_slice(&ZIP64_EXTRA_FIELD.to_le_bytes());
+                extra.extend_from_slice(&24u16.to_le_bytes());
+                extra.extend_from_slice(&(size as u64).to_le_bytes());
+                extra.extend_from_slice(&(stored.len() as u64).to_le_bytes());
+                extra.extend_from_slice(&u64::from(offset).to_le_bytes());
+            } else {
+                fields.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+                fields.extend_from_slice(&(size as u32).to_le_bytes());
+            }
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+
+            self.data.extend_from_slice(&LOCAL_FILE_HEADER.to_le_bytes());
+            self.data.extend_from_slice(&[20, 0, 0, 0]);
+            self.data.extend_from_slice(&fields);
+            self.data.extend_from_slice(&[0, 0]);
+            self.data.extend_from_slice(name.as_bytes());
+            self.data.extend_from_slice(stored);
+
+            self.directory.extend_from_slice(&CENTRAL_FILE_HEADER.to_le_bytes());
+            self.directory.extend_from_slice(&[20, 3, 20, 0, 0, 0]);
+            self.directory.extend_from_slice(&fields);
+            self.directory.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            self.directory.extend_from_slice(&[0; 10]);
+            let offset = if zip64 { 0xFFFF_FFFF } else { offset };
+            self.directory.extend_from_slice(&offset.to_le_bytes());
+            self.directory.extend_from_slice(name.as_bytes());
+            self.directory.extend_from_slice(&extra);
+            self.count += 1;
+        }
+
+        /// Appends the central directory, and the records which locate it.
+        fn finish(self, zip64: bool) -> Vec<u8> {
+            let Archive { mut data, directory, count } = self;
+            let offset = data.len() as u64;
+            let size = directory.len() as u64;
+            data.extend_from_slice(&directory);
+
+            if zip64 {
+                let record = data.len() as u64;
+                data.extend_from_slice(&ZIP64_END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+                data.extend_from_slice(&44u64.to_le_bytes());
+                data.extend_from_slice(&[45, 3, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+                data.extend_from_slice(&u64::from(count).to_le_bytes());
+                data.extend_from_slice(&u64::from(count).to_le_bytes());
+                data.extend_from_slice(&size.to_le_bytes());
+                data.extend_from_slice(&offset.to_le_bytes());
+
+                data.extend_from_slice(&ZIP64_LOCATOR.to_le_bytes());
+                data.extend_from_slice(&[0; 4]);
+                data.extend_from_slice(&record.to_le_bytes());
+                data.extend_from_slice(&1u32.to_le_bytes());
+            }
+
+            let (count, size, offset) = if zip64 {
+                (0xFFFF, 0xFFFF_FFFF, 0xFFFF_FFFF)
+            } else {
+                (count, size as u32, offset as u32)
+            };
+
+            data.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+            data.extend_from_slice(&[0; 4]);
+            data.extend_from_slice(&count.to_le_bytes());
+            data.extend_from_slice(&count.to_le_bytes());
+            data.extend_from_slice(&size.to_le_bytes());
+            data.extend_from_slice(&offset.to_le_bytes());
+            let comment = b"an archive comment";
+            data.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+            data.extend_from_slice(comment);
+            data
+        }
+    }
+
+    fn read(archive: &[u8], entry: &ZipEntry) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        entry.reader(Cursor::new(archive.to_vec()))?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn stored() {
+        let mut archive = Archive::default();
+        archive.add("docs/", STORED, b"", b"", false);
+        archive.add("README", STORED, b"read me", b"read me", false);
+        archive.add("disk.img", STORED, &[7; 3000], &[7; 3000], false);
+        let archive = archive.finish(false);
+
+        let entries = entries(&mut Cursor::new(&archive)).unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["README", "disk.img"]);
+        assert_eq!(entries[1].size, 3000);
+        assert!(entries[1].unsupported().is_none());
+        assert_eq!(read(&archive, &entries[0]).unwrap(), b"read me");
+        assert_eq!(read(&archive, &entries[1]).unwrap(), vec![7; 3000]);
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn deflated() {
+        use flate2::write::DeflateEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let deflated = encoder.finish().unwrap();
+
+        let mut archive = Archive::default();
+        archive.add("disk.img", DEFLATED, &data, &deflated, false);
+        let archive = archive.finish(false);
+
+        let entries = entries(&mut Cursor::new(&archive)).unwrap();
+        assert_eq!(entries[0].size, data.len() as u64);
+        assert_eq!(entries[0].compressed_size, deflated.len() as u64);
+        assert_eq!(read(&archive, &entries[0]).unwrap(), data);
+    }
+
+    #[test]
+    fn zip64() {
+        let mut archive = Archive::default();
+        archive.add("README", STORED, b"read me", b"read me", false);
+        archive.add("disk.img", STORED, b"disk image", b"disk image", true);
+        let archive = archive.finish(true);
+
+        let entries = entries(&mut Cursor::new(&archive)).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].size, 10);
+        assert_eq!(entries[1].compressed_size, 10);
+        assert_eq!(entries[1].header_offset, 30 + 6 + 7);
+        assert_eq!(read(&archive, &entries[1]).unwrap(), b"disk image");
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let mut archive = Archive::default();
+        archive.add_with_crc("disk.img", STORED, 10, b"disk image", crc32(b"disk imagf"), false);
+        let archive = archive.finish(false);
+
+        let entries = entries(&mut Cursor::new(&archive)).unwrap();
+        let why = read(&archive, &entries[0]).unwrap_err();
+        assert_eq!(why.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(why.to_string(), "zip entry failed its CRC-32 check");
+    }
+
+    #[test]
+    fn unsupported() {
+        let mut archive = Archive::default();
+        archive.add("disk.img", 14, b"lzma", b"lzma", false);
+        let archive = archive.finish(false);
+
+        let entries = entries(&mut Cursor::new(&archive)).unwrap();
+        assert!(entries[0].unsupported().is_some());
+        assert!(read(&archive, &entries[0]).is_err());
+    }
+
+    #[test]
+    fn end_of_central_directory() {
+        let mut archive = Archive::default();
+        archive.add("disk.img", STORED, b"disk image", b"disk image", false);
+        let archive = archive.finish(false);
+
+        // The record and its comment are cut off.
+        let truncated = &archive[..archive.len() - 30];
+        assert!(entries(&mut Cursor::new(truncated)).is_err());
+        assert!(entries(&mut Cursor::new(&archive[..10])).is_err());
+        assert!(entries(&mut Cursor::new(&[][..])).is_err());
+
+        // The record claims there is a zip64 record, but there is no locator.
+        let mut archive = Archive::default();
+        archive.add("disk.img", STORED, b"disk image", b"disk image", false);
+        let mut archive = archive.finish(true);
+        let locator = archive.len() - 22 - 18 - 20;
+        archive[locator] = 0;
+        let why = entries(&mut Cursor::new(&archive)).unwrap_err();
+        assert_eq!(why.to_string(), "zip64 end of central directory locator not found");
+    }
+
+    fn archive_of(names: &[&str]) -> Vec<ZipEntry> {
+        let mut archive = Archive::default();
+        for name in names {
+            archive.add(name, STORED, b"data", b"data", false);
+        }
+        entries(&mut Cursor::new(archive.finish(false))).unwrap()
+    }
+
+    #[test]
+    fn selection() {
+        // An archive of a single file holds the image, whatever it is named.
+        let entry = select_entry(archive_of(&["pop-os.bin"]), None).unwrap();
+        assert_eq!(entry.name, "pop-os.bin");
+
+        let entry = select_entry(archive_of(&["README", "SHA256SUMS", "out/pop-os.IMG"]), None);
+        assert_eq!(entry.unwrap().name, "out/pop-os.IMG");
+
+        match select_entry(archive_of(&["README", "a.img", "b.iso"]), None) {
+            Err(ImageError::AmbiguousArchive { candidates }) => {
+                assert_eq!(candidates, ["a.img", "b.iso"])
+            }
+            other => panic!("ambiguous archive was accepted: {:?}", other.map(|e| e.name)),
+        }
+
+        match select_entry(archive_of(&["README", "SHA256SUMS"]), None) {
+            Err(ImageError::NoImageInArchive) => (),
+            other => panic!("archive without an image was accepted: {:?}", other.map(|e| e.name)),
+        }
+
+        // Entries may be chosen by their full path, or by their file name.
+        let entry = select_entry(archive_of(&["a.img", "out/b.img"]), Some("b.img")).unwrap();
+        assert_eq!(entry.name, "out/b.img");
+        let entry = select_entry(archive_of(&["a.img", "out/b.img"]), Some("a.img")).unwrap();
+        assert_eq!(entry.name, "a.img");
+        let entry = select_entry(archive_of(&["README", "a.img"]), Some("README")).unwrap();
+        assert_eq!(entry.name, "README");
+
+        match select_entry(archive_of(&["a.img"]), Some("c.img")) {
+            Err(ImageError::EntryNotFound { entry }) => assert_eq!(entry, "c.img"),
+            other => panic!("missing entry was selected: {:?}", other.map(|e| e.name)),
+        }
+    }
+}
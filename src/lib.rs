@@ -1,179 +1,162 @@
+#![allow(non_local_definitions)]
+
+#[cfg(feature = "bzip2")]
+extern crate bzip2;
+extern crate crc32fast;
 extern crate failure;
 #[macro_use]
 extern crate failure_derive;
+#[cfg(feature = "flate2")]
+extern crate flate2;
 extern crate libc;
-
+extern crate sha2;
+#[cfg(feature = "xz2")]
+extern crate xz2;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+
+mod bmap;
+mod cancel;
+mod capacity;
+mod device;
+mod disk;
+mod image;
 mod mount;
-
+mod process;
+mod progress;
+mod resume;
+mod retry;
+mod monitor;
+mod stream;
+mod system;
+mod unmount;
+mod zeroes;
+
+pub use self::bmap::{BlockMap, BlockRange, BmapError};
+pub use self::cancel::CancelToken;
+pub use self::device::{Device, Transport};
+pub use self::disk::{device_size, is_read_only, Disk};
+pub use self::image::{Compression, Format, Image, ImageError};
+pub use self::monitor::{DeviceEvent, DeviceMonitor, NetlinkSource, UeventSource};
 pub use self::mount::Mount;
-
+pub use self::process::Process;
+pub use self::progress::{FlashEvent, FlashProgress};
+pub use self::resume::Resume;
+pub use self::retry::{BadBlock, RetryOptions};
+pub use self::stream::{Chunk, ImageStream, StreamInterrupted};
+pub use self::system::SystemDisks;
+pub use self::unmount::{release_disk, Released, UnmountOptions};
+
+use sha2::{Digest, Sha256};
 use std::cmp;
+use std::collections::hash_map::DefaultHasher;
 use std::ffi::OsString;
-use std::fs::{canonicalize, read_dir, File, OpenOptions};
-use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
-use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus};
-
-const BUFFER_SIZE: usize = 4 * 1024 * 1024;
-
-#[derive(Debug, Fail)]
-#[cfg_attr(rustfmt, rustfmt_skip)]
-pub enum ImageError {
-    #[fail(display = "image could not be opened: {}", why)]
-    Open { why: io::Error },
-    #[fail(display = "unable to get image metadata: {}", why)]
-    Metadata { why: io::Error },
-    #[fail(display = "image was not a file")]
-    NotAFile,
-    #[fail(display = "unable to read image: {}", why)]
-    ReadError { why: io::Error },
-    #[fail(display = "reached EOF prematurely")]
-    Eof,
-}
-
-/// A simple wrapper around a `File` that ensures that the file is a file, and
-/// obtains the file's size ahead of time.
-pub struct Image {
-    path: PathBuf,
-    file: File,
-    size: u64,
-}
-
-impl Image {
-    /// Opens the file and obtains the size from the metadata, then returns an
-    /// `Image` structure that contains the opened file and its file size.
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<Image, ImageError> {
-        let path = path.as_ref();
-        File::open(path)
-            .map_err(|why| ImageError::Open { why })
-            .and_then(|file| {
-                file.metadata()
-                    .map_err(|why| ImageError::Metadata { why })
-                    .and_then(|metadata| {
-                        if metadata.file_type().is_file() {
-                            Ok(Image {
-                                path: path.to_path_buf(),
-                                file,
-                                size: metadata.len(),
-                            })
-                        } else {
-                            Err(ImageError::NotAFile)
-                        }
-                    })
-            })
-    }
-
-    pub fn get_path(&self) -> &Path { &self.path }
-
-    /// Returns the size of the file, in bytes.
-    pub fn get_size(&self) -> u64 { self.size }
-
-    /// Reads the image into a vector, and reports progress to a callback.
-    pub fn read<P: FnMut(u64)>(
-        &mut self,
-        data: &mut Vec<u8>,
-        mut progress_callback: P,
-    ) -> Result<(), ImageError> {
-        if data.capacity() < self.size as usize {
-            let capacity = self.size as usize - data.capacity();
-            data.reserve_exact(capacity);
-            data.append(&mut vec![0; capacity])
-        } else if data.capacity() > self.size as usize {
-            data.truncate(self.size as usize);
-            data.shrink_to_fit();
-        }
-
-        let mut total = 0;
-        while total < data.len() {
-            let end = cmp::min(data.len(), total + BUFFER_SIZE);
-            let count = self.file
-                .read(&mut data[total..end])
-                .map_err(|why| ImageError::ReadError { why })?;
-
-            if count == 0 {
-                return Err(ImageError::Eof);
-            }
-            total += count;
-            progress_callback(total as u64);
-        }
-
-        Ok(())
-    }
-}
-
+use std::fs::canonicalize;
+use std::hash::Hasher;
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::os::unix::fs::FileTypeExt;
+use std::sync::Arc;
+use std::thread;
+
+use self::retry::Retrying;
+use self::stream::{aligned, BUFFER_ALIGN, BUFFER_SIZE};
+use self::zeroes::{is_zeroes, Zeroes};
+
+/// How often the progress of a flash is recorded, so that it may be resumed.
+const RECORD_INTERVAL: u64 = 64 * 1024 * 1024;
+/// How much of what was written before a flash was interrupted is read back
+/// and compared when it is resumed, as drives may lose what they had cached.
+const RESUME_WINDOW: u64 = 64 * 1024 * 1024;
+/// How many regions may fail to be written before the flash is abandoned.
+const MAX_BAD_BLOCKS: usize = 16;
+
+#[rustfmt::skip]
 #[derive(Debug, Fail)]
-#[cfg_attr(rustfmt, rustfmt_skip)]
 pub enum DiskError {
-    #[fail(display = "unable to open directory at '{}': {}", dir, why)]
-    Directory { dir: &'static str, why: io::Error },
-    #[fail(display = "unable to read directory entry at '{:?}': invalid UTF-8", dir)]
-    UTF8 { dir: PathBuf },
     #[fail(display = "unable to find disk '{}': {}", disk, why)]
     NoDisk { disk: String, why: io::Error },
-    #[fail(display = "failed to unmount {:?}: exit status {}", path, status)]
-    UnmountStatus { path: OsString, status: ExitStatus },
-    #[fail(display = "failed to unmount {:?}: {}", path, why)]
-    UnmountCommand { path: OsString, why: io::Error },
+    #[fail(display = "failed to unmount {:?} from disk '{}': {}", path, disk, why)]
+    Unmount { disk: String, path: OsString, why: io::Error },
+    #[fail(display = "failed to deactivate swap at {:?} on disk '{}': {}", path, disk, why)]
+    Swapoff { disk: String, path: PathBuf, why: io::Error },
     #[fail(display = "error using disk '{}': {:?} already mounted at {:?}", arg, source, dest)]
     AlreadyMounted { arg: String, source: OsString, dest: OsString },
     #[fail(display = "'{}' is not a block device", arg)]
     NotABlock { arg: String },
     #[fail(display = "unable to get metadata of disk '{}': {}", arg, why)]
     Metadata { arg: String, why: io::Error },
+    #[fail(display = "refusing to flash disk '{}', which holds {}", disk, holds)]
+    SystemDisk { disk: String, holds: String },
+    #[fail(display = "disk '{}' holds {} bytes, which is too small for an image of {} bytes", disk, size, image)]
+    TooSmall { disk: String, size: u64, image: u64 },
+    #[fail(display = "disk '{}' holds {} bytes, which is too small for the image", disk, size)]
+    ImageOverflow { disk: String, size: u64 },
+    #[fail(display = "disk '{}' claims to hold {} bytes, but only {} bytes are usable; it may be counterfeit", disk, size, usable)]
+    FakeCapacity { disk: String, size: u64, usable: u64 },
+    #[fail(display = "error probing the capacity of disk '{}': {}", disk, why)]
+    Probe { disk: String, why: io::Error },
+    #[fail(display = "disk '{}' is read-only or write-protected", disk)]
+    ReadOnly { disk: String },
     #[fail(display = "unable to open disk '{}': {}", disk, why)]
     Open { disk: String, why: io::Error },
+    #[fail(display = "disk '{}' is held exclusively by another process, or has a mounted filesystem", disk)]
+    Exclusive { disk: String },
     #[fail(display = "error writing disk '{}': {}", disk, why)]
     Write { disk: String, why: io::Error },
     #[fail(display = "error writing disk '{}': reached EOF", disk)]
     WriteEOF { disk: String },
+    #[fail(display = "error writing disk '{}': {} regions could not be written", disk, failed)]
+    BadBlocks { disk: String, failed: usize, blocks: Vec<BadBlock> },
+    #[fail(display = "error writing disk '{}': {}", disk, why)]
+    ImageStream { disk: String, why: StreamInterrupted },
     #[fail(display = "unable to flush disk '{}': {}", disk, why)]
     Flush { disk: String, why: io::Error },
-    #[fail(display = "error seeking disk '{}': seeked to {} instead of 0", disk, invalid)]
-    SeekInvalid { disk: String, invalid: u64 },
-    #[fail(display = "error seeking disk '{}': {}", disk, why)]
-    Seek { disk: String, why: io::Error },
+    #[fail(display = "flash of disk '{}' was cancelled", disk)]
+    Cancelled { disk: String },
     #[fail(display = "error verifying disk '{}': {}", disk, why)]
     Verify { disk: String, why: io::Error },
     #[fail(display = "error verifying disk '{}': reached EOF", disk)]
     VerifyEOF { disk: String },
     #[fail(display = "error verifying disk '{}': mismatch at {}:{}", disk, x, y)]
-    VerifyMismatch { disk: String, x: usize, y: usize },
+    VerifyMismatch { disk: String, x: u64, y: u64 },
+    #[fail(display = "error writing disk '{}': block map is for an image of {} bytes, but the image is {} bytes", disk, bmap, image)]
+    BmapImageSize { disk: String, bmap: u64, image: u64 },
+    #[fail(display = "error writing disk '{}': image data at {}:{} does not match the block map checksum", disk, x, y)]
+    BmapChecksum { disk: String, x: u64, y: u64 },
 }
 
-fn is_usb(filename: &str) -> bool {
-    filename.starts_with("pci-") && filename.contains("-usb-") && filename.ends_with("-0:0:0:0")
-}
-
-const DISK_DIR: &str = "/dev/disk/by-path/";
-
-/// Stores all discovered USB disk paths into the supplied `disks` vector.
-pub fn get_disk_args(disks: &mut Vec<String>) -> Result<(), DiskError> {
-    let readdir = read_dir(DISK_DIR).map_err(|why| DiskError::Directory { dir: DISK_DIR, why })?;
-
-    for entry_res in readdir {
-        let entry = entry_res.map_err(|why| DiskError::Directory { dir: DISK_DIR, why })?;
-        let path = entry.path();
-        if let Some(filename_os) = path.file_name() {
-            if is_usb(filename_os.to_str().unwrap()) {
-                disks.push(
-                    path.to_str()
-                        .ok_or_else(|| DiskError::UTF8 { dir: path.clone() })?
-                        .into(),
-                );
-            }
+impl DiskError {
+    /// The disk which could not be used because it is in use, if that is why
+    /// the error occurred. `Process::holding` may be used to find out why.
+    pub fn busy_disk(&self) -> Option<&str> {
+        match *self {
+            DiskError::AlreadyMounted { ref arg, .. } => Some(arg),
+            DiskError::Exclusive { ref disk } => Some(disk),
+            DiskError::Unmount { ref disk, .. } | DiskError::Swapoff { ref disk, .. } => Some(disk),
+            _ => None,
         }
     }
-
-    Ok(())
 }
 
+/// Opens each of the disks for writing, after ensuring that each one can hold
+/// an image of `image_size` bytes, if the size is known, is writable, and is
+/// not mounted. Mounted disks are refused, unless options are given for
+/// releasing them.
+///
+/// Disks which share a disk with the running system are refused. Passing a
+/// `SystemDisks::default()` overrides this.
+///
+/// Each disk is returned along with what was released from it, which is
+/// always empty when disks are not released.
 pub fn disks_from_args<D: Iterator<Item = String>>(
     disk_args: D,
     mounts: &[Mount],
-    unmount: bool,
-) -> Result<Vec<(String, File)>, DiskError> {
+    unmount: Option<&UnmountOptions>,
+    image_size: Option<u64>,
+    system: &SystemDisks,
+) -> Result<Vec<(String, Disk, Released)>, DiskError> {
     let mut disks = Vec::new();
 
     for disk_arg in disk_args {
@@ -182,45 +165,6 @@ pub fn disks_from_args<D: Iterator<Item = String>>(
             why,
         })?;
 
-        for mount in mounts.iter() {
-            if mount
-                .source
-                .as_bytes()
-                .starts_with(canonical_path.as_os_str().as_bytes())
-            {
-                if unmount {
-                    eprintln!(
-                        "unmounting '{}': {:?} is mounted at {:?}",
-                        disk_arg, mount.source, mount.dest
-                    );
-
-                    Command::new("umount")
-                        .arg(&mount.source)
-                        .status()
-                        .map_err(|why| DiskError::UnmountCommand {
-                            path: mount.source.clone(),
-                            why,
-                        })
-                        .and_then(|status| {
-                            if !status.success() {
-                                Err(DiskError::UnmountStatus {
-                                    path: mount.source.clone(),
-                                    status,
-                                })
-                            } else {
-                                Ok(())
-                            }
-                        })?;
-                } else {
-                    return Err(DiskError::AlreadyMounted {
-                        arg:    disk_arg.clone(),
-                        source: mount.source.clone(),
-                        dest:   mount.dest.clone(),
-                    });
-                }
-            }
-        }
-
         let metadata = canonical_path
             .metadata()
             .map_err(|why| DiskError::Metadata {
@@ -234,141 +178,639 @@ pub fn disks_from_args<D: Iterator<Item = String>>(
             });
         }
 
-        let disk = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .custom_flags(libc::O_SYNC)
-            .open(&canonical_path)
-            .map_err(|why| DiskError::Open {
-                disk: disk_arg.clone(),
-                why,
+        if let Some(holds) = system.holds(&canonical_path) {
+            return Err(DiskError::SystemDisk {
+                disk:  disk_arg,
+                holds: holds.to_owned(),
+            });
+        }
+
+        let size = device_size(&canonical_path).map_err(|why| DiskError::Metadata {
+            arg: disk_arg.clone(),
+            why,
+        })?;
+
+        if let Some(image_size) = image_size.filter(|&image_size| size < image_size) {
+            return Err(DiskError::TooSmall {
+                disk: disk_arg,
+                size,
+                image: image_size,
+            });
+        }
+
+        let read_only = is_read_only(&canonical_path).map_err(|why| DiskError::Metadata {
+            arg: disk_arg.clone(),
+            why,
+        })?;
+
+        if read_only {
+            return Err(DiskError::ReadOnly { disk: disk_arg });
+        }
+
+        let mut released = Released::default();
+        if let Some(options) = unmount {
+            released = release_disk(&canonical_path, mounts, options)?;
+        } else {
+            let mounted = Mount::of_disk(mounts, &canonical_path).map_err(|why| {
+                DiskError::Metadata {
+                    arg: disk_arg.clone(),
+                    why,
+                }
             })?;
 
-        disks.push((disk_arg, disk));
+            if let Some(mount) = mounted.first() {
+                return Err(DiskError::AlreadyMounted {
+                    arg:    disk_arg.clone(),
+                    source: mount.source.clone(),
+                    dest:   mount.dest.clone(),
+                });
+            }
+        }
+
+        let disk = Disk::open(&canonical_path).map_err(|why| {
+            if why.raw_os_error() == Some(libc::EBUSY) {
+                DiskError::Exclusive {
+                    disk: disk_arg.clone(),
+                }
+            } else {
+                DiskError::Open {
+                    disk: disk_arg.clone(),
+                    why,
+                }
+            }
+        })?;
+
+        disks.push((disk_arg, disk, released));
     }
 
     Ok(disks)
 }
 
-/// Writes an image to the specified disk.
-pub fn write_to_disk<M, F, S>(
-    mut message: M,
-    finish: F,
-    mut set: S,
-    mut disk: File,
+/// Options which control how an image is written to each disk.
+#[derive(Clone, Default)]
+pub struct WriteOptions {
+    /// Read the image back from the device after writing, bypassing the page
+    /// cache, and compare it.
+    pub check: bool,
+    /// Only write, and verify, the ranges of the image which are mapped.
+    pub bmap: Option<Arc<BlockMap>>,
+    /// Have the device zero, or discard, the chunks of the image which only
+    /// contain zeroes, rather than writing them. Discarded chunks are read
+    /// back, and written if the device does not return zeroes for them.
+    pub discard_zeroes: bool,
+    /// Detach the device once it has been flashed, so that it is safe to unplug.
+    pub eject: bool,
+    /// Check that the device can hold as much as it claims to, before writing.
+    /// Flashes which are resumed are not probed, as probing would overwrite
+    /// what was already written.
+    pub probe_capacity: bool,
+    /// Record the progress of the flash, and resume from an earlier record.
+    pub resume: Option<Resume>,
+    /// Retry writes which fail, rather than ending the flash.
+    pub retry: RetryOptions,
+}
+
+/// What became of a disk once an image was written to it.
+#[derive(Clone, Debug, Default)]
+pub struct Flashed {
+    /// Whether the kernel re-read the partition table that was written.
+    pub rescanned: bool,
+    /// Whether the device can hold partitions at all. Those which cannot have
+    /// no partition table to re-read.
+    pub partitionable: bool,
+    /// The partitions which the kernel found on the disk afterwards.
+    pub partitions: Vec<PathBuf>,
+    /// Whether the device was detached, and so is safe to unplug. Devices
+    /// which could not be detached are left attached, without an error.
+    pub ejected: bool,
+    /// The regions which were only written after being retried.
+    pub bad_blocks: Vec<BadBlock>,
+}
+
+/// Writes an image to the specified disk, as it is being read from the stream.
+///
+/// Once written, and verified, the disk is synced and its partition table is
+/// re-read, so that its new partitions become visible.
+///
+/// Each phase of the flash is reported to `progress`, which always ends with
+/// `FlashEvent::Finished`, `FlashEvent::Cancelled` or `FlashEvent::Failed`.
+///
+/// Once `cancel` is cancelled, the flash stops before its next chunk, and
+/// ends with `DiskError::Cancelled`. What was written so far is synced, and
+/// recorded if the flash is to be resumed.
+pub fn write_to_disk<P: FlashProgress>(
+    mut progress: P,
+    cancel: &CancelToken,
+    mut disk: Disk,
     disk_path: String,
-    image_size: u64,
-    image_data: &[u8],
-    check: bool,
-) -> Result<(), DiskError>
-where
-    M: FnMut(&str),
-    F: Fn(),
-    S: FnMut(u64),
-{
-    let mut total = 0;
-    while total < image_data.len() {
-        let end = cmp::min(image_size as usize, total + BUFFER_SIZE);
-        let count = disk.write(&image_data[total..end]).map_err(|why| {
-            message(&format!("! {}: ", disk_path));
-            finish();
-            DiskError::Write {
-                disk: disk_path.clone(),
-                why,
-            }
-        })?;
+    stream: ImageStream,
+    options: &WriteOptions,
+) -> Result<Flashed, DiskError> {
+    progress.event(FlashEvent::Started);
+    let mut result = write_image(&mut progress, cancel, &mut disk, &disk_path, &stream, options);
+
+    // The disk must be closed, releasing its claim on the device, before it can be ejected.
+    drop(disk);
+    if options.eject {
+        if let Ok(ref mut flashed) = result {
+            // The image was written regardless, and so a device which cannot
+            // be detached, such as a built-in card reader, is left attached.
+            flashed.ejected = Device::from_path(&disk_path)
+                .and_then(|device| device.eject())
+                .is_ok();
+        }
+    }
 
-        if count == 0 {
-            message(&format!("! {}: ", disk_path));
-            finish();
+    progress.event(match result {
+        Ok(_) => FlashEvent::Finished,
+        Err(DiskError::Cancelled { .. }) => FlashEvent::Cancelled,
+        Err(ref why) => FlashEvent::Failed {
+            error: why.to_string(),
+        },
+    });
 
-            return Err(DiskError::WriteEOF {
-                disk: disk_path.clone(),
+    result
+}
+
+fn write_image<P: FlashProgress>(
+    progress: &mut P,
+    cancel: &CancelToken,
+    disk: &mut Disk,
+    disk_path: &str,
+    stream: &ImageStream,
+    options: &WriteOptions,
+) -> Result<Flashed, DiskError> {
+    let capacity = disk.size().map_err(|why| DiskError::Metadata {
+        arg: disk_path.into(),
+        why,
+    })?;
+
+    // Images whose size is not known are instead checked as they are written.
+    if let Some(size) = stream.size().filter(|&size| capacity < size) {
+        return Err(DiskError::TooSmall {
+            disk:  disk_path.into(),
+            size:  capacity,
+            image: size,
+        });
+    }
+
+    if let (Some(bmap), Some(size)) = (options.bmap.as_ref(), stream.size()) {
+        if bmap.image_size() != size {
+            return Err(DiskError::BmapImageSize {
+                disk:  disk_path.into(),
+                bmap:  bmap.image_size(),
+                image: size,
             });
         }
-        total += count;
-        set(total as u64);
     }
 
-    disk.flush().map_err(|why| {
-        message(&format!("! {}: ", disk_path));
-        finish();
-
-        DiskError::Flush {
-            disk: disk_path.clone(),
+    // Devices are told apart by their serial, which devices such as loop
+    // devices lack, and so those are never resumed.
+    let serial = match options.resume {
+        Some(_) => Device::from_path(disk_path).map_or_else(|_| String::new(), |device| device.serial),
+        None => String::new(),
+    };
+
+    let mut resuming = Resuming::new(
+        options
+            .resume
+            .as_ref()
+            .map_or(0, |resume| resume.offset(&serial))
+            .min(stream.size().unwrap_or(capacity)),
+    );
+
+    // Probing overwrites blocks across the disk, including those which were
+    // already flashed, and so a flash which is being resumed is not probed.
+    if options.probe_capacity && resuming.from == 0 {
+        progress.event(FlashEvent::Probing);
+        let probed = capacity::probe(disk, capacity).map_err(|why| DiskError::Probe {
+            disk: disk_path.into(),
             why,
+        })?;
+
+        if let Some(usable) = probed {
+            return Err(DiskError::FakeCapacity {
+                disk: disk_path.into(),
+                size: capacity,
+                usable,
+            });
         }
-    })?;
+    }
 
-    if check {
-        match disk.seek(SeekFrom::Start(0)) {
-            Ok(0) => (),
-            Ok(invalid) => {
-                message(&format!("! {}: ", disk_path));
-                finish();
+    if resuming.from != 0 {
+        // The trailing window must be read back from the device itself.
+        disk.bypass_cache().map_err(|why| DiskError::Verify {
+            disk: disk_path.into(),
+            why,
+        })?;
+    }
 
-                return Err(DiskError::SeekInvalid {
-                    disk: disk_path.clone(),
-                    invalid,
-                });
+    let mut zeroing = Zeroing::new(if options.discard_zeroes {
+        Zeroes::prepare(disk.file(), resuming.from, stream.size().unwrap_or(capacity))
+    } else {
+        Zeroes::Write
+    });
+
+    let mut recorded = resuming.from;
+    let mut retrying = Retrying::new(&options.retry);
+
+    // The image is not kept in memory, so a hash of each region is recorded in
+    // order to verify what was written afterwards.
+    let mut written = Vec::new();
+
+    // With a block map, only the data within each mapped range is written.
+    let mut next_range = 0;
+    let mut range_hasher = Sha256::new();
+
+    let mut reached = resuming.from;
+    loop {
+        if cancel.is_cancelled() {
+            disk.flush().map_err(|why| DiskError::Flush {
+                disk: disk_path.into(),
+                why,
+            })?;
+
+            // What was written so far is recorded, so that the flash may be resumed.
+            if let Some(ref resume) = options.resume {
+                if retrying.failed() == 0 {
+                    let _ = resume.save(&serial, reached);
+                }
             }
+
+            return Err(DiskError::Cancelled {
+                disk: disk_path.into(),
+            });
+        }
+
+        let chunk = match stream.next_chunk() {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
             Err(why) => {
-                message(&format!("! {}: ", disk_path));
-                finish();
+                return Err(DiskError::ImageStream {
+                    disk: disk_path.into(),
+                    why,
+                })
+            }
+        };
+
+        let start = chunk.offset();
+        let end = start + chunk.len() as u64;
+        if end > capacity {
+            return Err(DiskError::ImageOverflow {
+                disk: disk_path.into(),
+                size: capacity,
+            });
+        }
 
-                return Err(DiskError::Seek {
-                    disk: disk_path.clone(),
+        match options.bmap {
+            Some(ref bmap) => {
+                while let Some(range) = bmap.ranges().get(next_range) {
+                    if range.start >= end {
+                        break;
+                    }
+
+                    let from = cmp::max(range.start, start);
+                    let to = cmp::min(range.end, end);
+                    if from < to {
+                        let data = &chunk[(from - start) as usize..(to - start) as usize];
+                        resume_data(
+                            disk,
+                            disk_path,
+                            &mut zeroing,
+                            &mut resuming,
+                            &mut retrying,
+                            from,
+                            data,
+                        )?;
+                        range_hasher.update(data);
+                        if options.check {
+                            written.push((from, data.len(), hash(data)));
+                        }
+                    }
+
+                    if range.end > end {
+                        break;
+                    }
+
+                    // The range has been fully written, so its checksum can be compared.
+                    let sum = mem::replace(&mut range_hasher, Sha256::new()).finalize();
+                    if range.checksum.is_some_and(|expected| sum[..] != expected[..]) {
+                        return Err(DiskError::BmapChecksum {
+                            disk: disk_path.into(),
+                            x:    range.start,
+                            y:    range.end,
+                        });
+                    }
+
+                    next_range += 1;
+                }
+            }
+            None => {
+                resume_data(
+                    disk,
+                    disk_path,
+                    &mut zeroing,
+                    &mut resuming,
+                    &mut retrying,
+                    start,
+                    &chunk,
+                )?;
+                if options.check {
+                    written.push((start, chunk.len(), hash(&chunk)));
+                }
+            }
+        }
+
+        progress.event(FlashEvent::Writing { bytes: end });
+        reached = cmp::max(reached, end);
+
+        if let Some(ref resume) = options.resume {
+            // Regions which failed must be written again when the flash is resumed.
+            let failed = retrying.failed() != 0;
+            if end.saturating_sub(recorded) >= RECORD_INTERVAL && !serial.is_empty() && !failed {
+                disk.flush().map_err(|why| DiskError::Flush {
+                    disk: disk_path.into(),
                     why,
-                });
+                })?;
+
+                // The record only saves time, so the flash goes on without it.
+                let _ = resume.save(&serial, end);
+                recorded = end;
             }
         }
+    }
+
+    progress.event(FlashEvent::Syncing);
+    disk.flush().map_err(|why| DiskError::Flush {
+        disk: disk_path.into(),
+        why,
+    })?;
+
+    let failed = retrying.failed();
+    if failed != 0 {
+        return Err(DiskError::BadBlocks {
+            disk: disk_path.into(),
+            failed,
+            blocks: retrying.blocks,
+        });
+    }
+
+    if let Some(ref resume) = options.resume {
+        let _ = resume.save(&serial, reached);
+    }
 
-        message(&format!("V {}: ", disk_path));
-        set(0);
-        total = 0;
-
-        let mut buf = vec![0; BUFFER_SIZE];
-        while total < image_data.len() {
-            let end = cmp::min(image_size as usize, total + BUFFER_SIZE);
-            let count = match disk.read(&mut buf[..end - total]) {
-                Ok(count) => count,
-                Err(why) => {
-                    message(&format!("! {}: ", disk_path));
-                    finish();
-
-                    return Err(DiskError::Verify {
-                        disk: disk_path.clone(),
-                        why,
-                    });
+    if options.check {
+        if let Err(why) = verify_disk(progress, cancel, disk, disk_path, written) {
+            // A disk which does not hold what was written must be written again
+            // in full, rather than resumed from the end of the image. Cancelling
+            // the verification says nothing about the disk, so that is resumed.
+            if let Some(ref resume) = options.resume {
+                if !matches!(why, DiskError::Cancelled { .. }) {
+                    let _ = resume.clear(&serial);
                 }
-            };
+            }
+
+            return Err(why);
+        }
+    }
 
-            if count == 0 {
-                message(&format!("! {}: ", disk_path));
-                finish();
+    if let Some(ref resume) = options.resume {
+        let _ = resume.clear(&serial);
+    }
 
-                return Err(DiskError::VerifyEOF {
-                    disk: disk_path.clone(),
-                });
+    let rescanned = disk.finish().map_err(|why| DiskError::Flush {
+        disk: disk_path.into(),
+        why,
+    })?;
+
+    Ok(Flashed {
+        rescanned,
+        partitionable: disk.is_partitionable(),
+        partitions: disk.partitions(),
+        ejected: false,
+        bad_blocks: retrying.blocks,
+    })
+}
+
+/// Reads back each region that was written, and compares it with the hash
+/// that was recorded for it.
+fn verify_disk<P: FlashProgress>(
+    progress: &mut P,
+    cancel: &CancelToken,
+    disk: &mut Disk,
+    disk_path: &str,
+    written: Vec<(u64, usize, u64)>,
+) -> Result<(), DiskError> {
+    progress.event(FlashEvent::Verifying { bytes: 0 });
+
+    disk.bypass_cache().map_err(|why| DiskError::Verify {
+        disk: disk_path.into(),
+        why,
+    })?;
+
+    let mut buffer = vec![0; BUFFER_SIZE + BUFFER_ALIGN];
+    let buffer = aligned(&mut buffer, BUFFER_ALIGN, BUFFER_SIZE);
+    for (offset, len, expected) in written {
+        if cancel.is_cancelled() {
+            return Err(DiskError::Cancelled {
+                disk: disk_path.into(),
+            });
+        }
+
+        disk.read_at(offset, &mut buffer[..len]).map_err(|why| match why.kind() {
+            io::ErrorKind::UnexpectedEof => DiskError::VerifyEOF {
+                disk: disk_path.into(),
+            },
+            _ => DiskError::Verify {
+                disk: disk_path.into(),
+                why,
+            },
+        })?;
+
+        if hash(&buffer[..len]) != expected {
+            return Err(DiskError::VerifyMismatch {
+                disk: disk_path.into(),
+                x:    offset,
+                y:    offset + len as u64,
+            });
+        }
+
+        progress.event(FlashEvent::Verifying {
+            bytes: offset + len as u64,
+        });
+    }
+
+    Ok(())
+}
+
+/// Where a flash which is being resumed continues from.
+struct Resuming {
+    /// The offset before which the image was already written.
+    from:   u64,
+    buffer: Vec<u8>,
+}
+
+impl Resuming {
+    fn new(from: u64) -> Resuming {
+        Resuming {
+            from,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// Writes the data at `offset`, except for the part which was written before
+/// the flash was interrupted. That part is compared with what is on the disk
+/// if it lies within the trailing window before the offset the flash resumes
+/// from, and the flash instead resumes from there if it differs.
+fn resume_data(
+    disk: &mut Disk,
+    disk_path: &str,
+    zeroing: &mut Zeroing,
+    resuming: &mut Resuming,
+    retrying: &mut Retrying,
+    offset: u64,
+    data: &[u8],
+) -> Result<(), DiskError> {
+    if offset >= resuming.from {
+        return write_data(disk, disk_path, zeroing, retrying, offset, data);
+    }
+
+    let written = cmp::min(data.len() as u64, resuming.from - offset) as usize;
+    let window = resuming.from.saturating_sub(RESUME_WINDOW);
+    let mut skip = written;
+    if offset + written as u64 > window {
+        let at = cmp::max(offset, window);
+        let expected = &data[(at - offset) as usize..written];
+        resuming.buffer.resize(expected.len(), 0);
+        match disk.read_at(at, &mut resuming.buffer) {
+            Ok(()) if resuming.buffer[..] == *expected => (),
+            _ => {
+                resuming.from = at;
+                skip = (at - offset) as usize;
             }
+        }
+    }
+
+    if skip < data.len() {
+        write_data(disk, disk_path, zeroing, retrying, offset + skip as u64, &data[skip..])?;
+    }
+
+    Ok(())
+}
+
+/// How regions of zeroes are dealt with, along with the buffer which those
+/// that were skipped are read back into.
+struct Zeroing {
+    zeroes: Zeroes,
+    buffer: Vec<u8>,
+}
 
-            if buf[..count] != image_data[total..total + count] {
-                message(&format!("! {}: ", disk_path));
-                finish();
+impl Zeroing {
+    fn new(zeroes: Zeroes) -> Zeroing {
+        Zeroing {
+            zeroes,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Whether the `len` bytes at `offset` are read back from the disk as
+    /// zeroes. Regions are no larger than a chunk, so one buffer holds each.
+    fn reads_zeroes(&mut self, disk: &mut Disk, offset: u64, len: usize) -> bool {
+        if self.buffer.is_empty() {
+            self.buffer = vec![0; BUFFER_SIZE + BUFFER_ALIGN];
+        }
+
+        let buffer = aligned(&mut self.buffer, BUFFER_ALIGN, len);
+        disk.read_at(offset, buffer).is_ok() && is_zeroes(buffer)
+    }
+}
+
+/// Writes the data at `offset`, unless it only contains zeroes that the
+/// device can take care of by itself.
+///
+/// Writes which fail are retried after reopening the disk, as the error may
+/// have left its handle unusable, such as when the drive was reset. As each
+/// write is positioned, nothing else needs to be restored. Regions which
+/// cannot be written are recorded, and skipped, until too many have failed.
+fn write_data(
+    disk: &mut Disk,
+    disk_path: &str,
+    zeroing: &mut Zeroing,
+    retrying: &mut Retrying,
+    offset: u64,
+    data: &[u8],
+) -> Result<(), DiskError> {
+    let len = data.len() as u64;
+    let zeroes = zeroing.zeroes;
+    if zeroes != Zeroes::Write && is_zeroes(data) && zeroes.handle(disk.file(), offset, len) {
+        // Devices need not read discarded regions back as zeroes, so those
+        // which do not are written after all.
+        if !matches!(zeroes, Zeroes::Skip(_)) || zeroing.reads_zeroes(disk, offset, data.len()) {
+            return Ok(());
+        }
+    }
 
-                return Err(DiskError::VerifyMismatch {
-                    disk: disk_path.clone(),
-                    x:    total,
-                    y:    total + count,
+    let options = retrying.options;
+    let mut retries = 0;
+    let mut error = None;
+    while let Err(why) = disk.write_at(offset, data) {
+        if why.kind() == io::ErrorKind::WriteZero {
+            return Err(DiskError::WriteEOF {
+                disk: disk_path.into(),
+            });
+        } else if options.attempts == 0 {
+            return Err(DiskError::Write {
+                disk: disk_path.into(),
+                why,
+            });
+        } else if retries == options.attempts {
+            retrying.blocks.push(BadBlock {
+                offset,
+                len,
+                retries,
+                recovered: false,
+                error: why.to_string(),
+            });
+
+            let failed = retrying.failed();
+            if failed >= MAX_BAD_BLOCKS {
+                return Err(DiskError::BadBlocks {
+                    disk: disk_path.into(),
+                    failed,
+                    blocks: mem::take(&mut retrying.blocks),
                 });
             }
 
-            total += count;
-            set(total as u64);
+            return Ok(());
         }
+
+        retries += 1;
+        error = Some(why.to_string());
+        thread::sleep(options.delay(retries));
+
+        // A disk which cannot be reopened is gone, so retrying is pointless.
+        disk.recover().map_err(|why| DiskError::Write {
+            disk: disk_path.into(),
+            why,
+        })?;
     }
 
-    finish();
+    if let Some(error) = error {
+        retrying.blocks.push(BadBlock {
+            offset,
+            len,
+            retries,
+            recovered: true,
+            error,
+        });
+    }
 
     Ok(())
 }
+
+fn hash(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(data);
+    hasher.finish()
+}
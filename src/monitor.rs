@@ -0,0 +1,376 @@
+//! Watches for block devices being connected and disconnected, by listening
+//! to the uevents that the kernel broadcasts over netlink.
+
+use libc;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+
+use super::device::{Device, SYS_BLOCK};
+
+/// The multicast group which the kernel sends its uevents to. Group 2 is
+/// used by udev to rebroadcast them, after its rules have been applied.
+const KERNEL_GROUP: u32 = 1;
+const UEVENT_BUFFER_SIZE: usize = 8192;
+
+/// A change to the block devices on the system.
+#[derive(Clone, Debug)]
+pub enum DeviceEvent {
+    Added(Device),
+    /// The device was disconnected, or its media was ejected.
+    Removed { name: String, path: PathBuf },
+}
+
+/// Supplies raw uevent messages to a `DeviceMonitor`.
+pub trait UeventSource {
+    /// Blocks until the next message is received.
+    fn recv(&mut self) -> io::Result<Vec<u8>>;
+}
+
+/// Receives uevents from the kernel through a netlink socket.
+pub struct NetlinkSource {
+    fd:     RawFd,
+    buffer: Vec<u8>,
+}
+
+impl NetlinkSource {
+    pub fn new() -> io::Result<NetlinkSource> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let source = NetlinkSource {
+            fd,
+            buffer: vec![0; UEVENT_BUFFER_SIZE],
+        };
+
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = KERNEL_GROUP;
+        let bound = unsafe {
+            libc::bind(
+                fd,
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+
+        if bound == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(source)
+    }
+}
+
+impl UeventSource for NetlinkSource {
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            let mut sender: libc::sockaddr_nl = unsafe { mem::zeroed() };
+            let mut sender_len = mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t;
+            let read = unsafe {
+                libc::recvfrom(
+                    self.fd,
+                    self.buffer.as_mut_ptr() as *mut libc::c_void,
+                    self.buffer.len(),
+                    0,
+                    &mut sender as *mut libc::sockaddr_nl as *mut libc::sockaddr,
+                    &mut sender_len,
+                )
+            };
+
+            if read == -1 {
+                let why = io::Error::last_os_error();
+                if why.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(why);
+            }
+
+            // Any process may send to the group, so only the kernel is trusted.
+            if sender.nl_pid == 0 {
+                return Ok(self.buffer[..read as usize].to_vec());
+            }
+        }
+    }
+}
+
+impl Drop for NetlinkSource {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Reports block devices as they are added to and removed from the system.
+///
+/// ```no_run
+/// use popsicle::{DeviceEvent, DeviceMonitor};
+///
+/// let mut monitor = DeviceMonitor::new().unwrap();
+/// while let Ok(event) = monitor.next_event() {
+///     match event {
+///         DeviceEvent::Added(device) => println!("added {}", device.path.display()),
+///         DeviceEvent::Removed { path, .. } => println!("removed {}", path.display()),
+///     }
+/// }
+/// ```
+pub struct DeviceMonitor<S = NetlinkSource> {
+    source:    S,
+    sys_block: PathBuf,
+}
+
+impl DeviceMonitor<NetlinkSource> {
+    /// Listens for the uevents of the kernel.
+    pub fn new() -> io::Result<DeviceMonitor> { NetlinkSource::new().map(DeviceMonitor::with_source) }
+}
+
+impl<S: UeventSource> DeviceMonitor<S> {
+    /// Reads uevents from another source, such as messages made by tests.
+    pub fn with_source(source: S) -> DeviceMonitor<S> { DeviceMonitor::with_sysfs(source, SYS_BLOCK) }
+
+    /// Reads uevents from another source, and describes the devices which
+    /// they add from `sys_block` rather than from `/sys/class/block`, so that
+    /// tests may supply devices which are not on the system.
+    pub fn with_sysfs<P: Into<PathBuf>>(source: S, sys_block: P) -> DeviceMonitor<S> {
+        DeviceMonitor {
+            source,
+            sys_block: sys_block.into(),
+        }
+    }
+
+    /// Blocks until a whole disk is added or removed. Partitions are ignored.
+    ///
+    /// Card readers remain present while they are empty, so the insertion and
+    /// removal of media are reported as their reader being added and removed.
+    pub fn next_event(&mut self) -> io::Result<DeviceEvent> {
+        loop {
+            let message = self.source.recv()?;
+            if let Some(event) = parse(&message, &self.sys_block) {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+impl<S: UeventSource> Iterator for DeviceMonitor<S> {
+    type Item = io::Result<DeviceEvent>;
+
+    fn next(&mut self) -> Option<io::Result<DeviceEvent>> { Some(self.next_event()) }
+}
+
+/// Parses a uevent, which is a header such as `add@/devices/...`, followed
+/// by `KEY=value` pairs, each of which is terminated by a null byte.
+fn parse(message: &[u8], sys_block: &Path) -> Option<DeviceEvent> {
+    let mut fields = message.split(|&byte| byte == 0);
+    if !fields.next()?.contains(&b'@') {
+        return None;
+    }
+
+    let (mut action, mut subsystem, mut devtype, mut name) = (None, None, None, None);
+    for field in fields {
+        let field = match ::std::str::from_utf8(field) {
+            Ok(field) => field,
+            Err(_) => continue,
+        };
+
+        let mut pair = field.splitn(2, '=');
+        match (pair.next(), pair.next()) {
+            (Some("ACTION"), Some(value)) => action = Some(value),
+            (Some("SUBSYSTEM"), Some(value)) => subsystem = Some(value),
+            (Some("DEVTYPE"), Some(value)) => devtype = Some(value),
+            (Some("DEVNAME"), Some(value)) => name = Some(value),
+            _ => (),
+        }
+    }
+
+    if subsystem != Some("block") || devtype != Some("disk") {
+        return None;
+    }
+
+    // Names are relative to `/dev`, but may contain directories.
+    let name = name?.trim_start_matches("/dev/");
+    if name.is_empty() || name.split('/').any(|part| part == "..") {
+        return None;
+    }
+
+    let removed = || DeviceEvent::Removed {
+        name: name.to_owned(),
+        path: PathBuf::from("/dev").join(name),
+    };
+
+    match action? {
+        "add" | "change" => match Device::from_sys(sys_block, name) {
+            Ok(ref device) if device.size == 0 => Some(removed()),
+            Ok(device) => Some(DeviceEvent::Added(device)),
+            // The device may already be gone again.
+            Err(_) => None,
+        },
+        "remove" => Some(removed()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    /// Supplies the given messages, and then fails as though the socket closed.
+    struct Messages(VecDeque<Vec<u8>>);
+
+    impl UeventSource for Messages {
+        fn recv(&mut self) -> io::Result<Vec<u8>> {
+            self.0
+                .pop_front()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "no more messages"))
+        }
+    }
+
+    fn uevent(action: &str, subsystem: &str, devtype: &str, name: &str) -> Vec<u8> {
+        let fields = [
+            format!("{}@/devices/pci0000:00/usb2/2-1/block/{}", action, name),
+            format!("ACTION={}", action),
+            format!("DEVPATH=/devices/pci0000:00/usb2/2-1/block/{}", name),
+            format!("SUBSYSTEM={}", subsystem),
+            format!("DEVNAME={}", name),
+            format!("DEVTYPE={}", devtype),
+            "SEQNUM=4242".into(),
+        ];
+
+        let mut message = Vec::new();
+        for field in &fields {
+            message.extend_from_slice(field.as_bytes());
+            message.push(0);
+        }
+        message
+    }
+
+    /// A sysfs tree holding a USB drive named `sdb`, of `sectors` sectors.
+    struct Sysfs(PathBuf);
+
+    impl Sysfs {
+        fn new(test: &str, sectors: u64) -> Sysfs {
+            let root = ::std::env::temp_dir().join(format!("popsicle-{}-{}", test, process::id()));
+            let usb = root.join("devices/pci0000:00/usb2/2-1");
+            let disk = usb.join("block/sdb");
+            fs::create_dir_all(disk.join("device")).unwrap();
+            fs::create_dir_all(root.join("class/block")).unwrap();
+            fs::write(usb.join("idVendor"), "0781\n").unwrap();
+            fs::write(usb.join("serial"), "4C530001\n").unwrap();
+            fs::write(disk.join("size"), format!("{}\n", sectors)).unwrap();
+            fs::write(disk.join("removable"), "1\n").unwrap();
+            fs::write(disk.join("device/vendor"), "SanDisk\n").unwrap();
+            fs::write(disk.join("device/model"), "Cruzer_Blade\n").unwrap();
+            symlink(&disk, root.join("class/block/sdb")).unwrap();
+            Sysfs(root)
+        }
+
+        fn monitor(&self, messages: Vec<Vec<u8>>) -> DeviceMonitor<Messages> {
+            DeviceMonitor::with_sysfs(Messages(messages.into()), self.0.join("class/block"))
+        }
+    }
+
+    impl Drop for Sysfs {
+        fn drop(&mut self) { let _ = fs::remove_dir_all(&self.0); }
+    }
+
+    #[test]
+    fn added() {
+        let sysfs = Sysfs::new("added", 8192);
+        let mut monitor = sysfs.monitor(vec![uevent("add", "block", "disk", "sdb")]);
+        match monitor.next_event().unwrap() {
+            DeviceEvent::Added(device) => {
+                assert_eq!(device.name, "sdb");
+                assert_eq!(device.path, PathBuf::from("/dev/sdb"));
+                assert_eq!(device.size, 8192 * 512);
+                assert_eq!(device.serial, "4C530001");
+                assert_eq!(device.label(), "SanDisk Cruzer Blade");
+                assert!(device.is_portable());
+            }
+            event => panic!("expected the drive to be added, not {:?}", event),
+        }
+    }
+
+    #[test]
+    fn media_removed() {
+        // Card readers remain, without any media, once their card is removed.
+        let sysfs = Sysfs::new("media-removed", 0);
+        let mut monitor = sysfs.monitor(vec![uevent("change", "block", "disk", "sdb")]);
+        match monitor.next_event().unwrap() {
+            DeviceEvent::Removed { name, .. } => assert_eq!(name, "sdb"),
+            event => panic!("expected the media to be removed, not {:?}", event),
+        }
+    }
+
+    #[test]
+    fn removed() {
+        // Removed devices are already gone from sysfs.
+        let mut monitor = DeviceMonitor::with_sysfs(
+            Messages(vec![uevent("remove", "block", "disk", "sdz")].into()),
+            "/nonexistent",
+        );
+        match monitor.next_event().unwrap() {
+            DeviceEvent::Removed { name, path } => {
+                assert_eq!(name, "sdz");
+                assert_eq!(path, PathBuf::from("/dev/sdz"));
+            }
+            event => panic!("expected the drive to be removed, not {:?}", event),
+        }
+    }
+
+    #[test]
+    fn ignored() {
+        let sysfs = Sysfs::new("ignored", 8192);
+        let mut no_header = uevent("add", "block", "disk", "sdb");
+        no_header.retain(|&byte| byte != b'@');
+
+        let mut monitor = sysfs.monitor(vec![
+            uevent("add", "block", "partition", "sdb1"),
+            uevent("add", "scsi_disk", "disk", "sdb"),
+            no_header,
+            uevent("bind", "block", "disk", "sdb"),
+            // Devices which are already gone again are skipped.
+            uevent("add", "block", "disk", "sdc"),
+            uevent("remove", "block", "disk", "sdb"),
+        ]);
+
+        match monitor.next_event().unwrap() {
+            DeviceEvent::Removed { name, .. } => assert_eq!(name, "sdb"),
+            event => panic!("expected only the removal to be reported, not {:?}", event),
+        }
+        assert!(monitor.next_event().is_err());
+    }
+
+    #[test]
+    fn parent_directories() {
+        let sysfs = Sysfs::new("parent-directories", 8192);
+        let mut monitor = sysfs.monitor(vec![
+            uevent("remove", "block", "disk", "../sda"),
+            uevent("add", "block", "disk", "/dev/../../etc/passwd"),
+            uevent("remove", "block", "disk", "/dev/"),
+            uevent("remove", "block", "disk", "/dev/mapper/root"),
+        ]);
+
+        match monitor.next_event().unwrap() {
+            DeviceEvent::Removed { name, path } => {
+                assert_eq!(name, "mapper/root");
+                assert_eq!(path, PathBuf::from("/dev/mapper/root"));
+            }
+            event => panic!("expected only the removal to be reported, not {:?}", event),
+        }
+    }
+}
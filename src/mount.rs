@@ -1,20 +1,26 @@
-use std::char;
+use libc;
 use std::ffi::OsString;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Result};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Error, Result};
 use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
 
+/// A mounted filesystem, as listed in `/proc/self/mountinfo`.
 pub struct Mount {
+    /// The device number of the filesystem, which is anonymous for filesystems
+    /// that are not backed by a single block device.
+    pub device:  u64,
+    /// The directory within the filesystem which is mounted.
+    pub root:    OsString,
     pub source:  OsString,
     pub dest:    OsString,
     pub fs:      OsString,
     pub options: OsString,
-    pub dump:    OsString,
-    pub pass:    OsString,
 }
 
 impl Mount {
-    fn parse_value(value: &str) -> Result<OsString> {
+    pub(crate) fn parse_value(value: &str) -> Result<OsString> {
         let mut ret = Vec::new();
 
         let mut bytes = value.bytes();
@@ -26,9 +32,9 @@ impl Mount {
                         if let Some(b) = bytes.next() {
                             code *= 8;
                             code += u32::from_str_radix(&(b as char).to_string(), 8)
-                                .map_err(|err| Error::new(ErrorKind::Other, err))?;
+                                .map_err(Error::other)?;
                         } else {
-                            return Err(Error::new(ErrorKind::Other, "truncated octal code"));
+                            return Err(Error::other("truncated octal code"));
                         }
                     }
                     ret.push(code as u8);
@@ -42,42 +48,76 @@ impl Mount {
         Ok(OsString::from_vec(ret))
     }
 
+    /// Parses a line of mountinfo, such as:
+    ///
+    /// `36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue`
     fn parse_line(line: &str) -> Result<Mount> {
         let mut parts = line.split(' ');
 
-        let source = parts
+        let device = parts
+            .nth(2)
+            .ok_or(Error::other("Missing device"))?;
+        let root = parts
             .next()
-            .ok_or(Error::new(ErrorKind::Other, "Missing source"))?;
+            .ok_or(Error::other("Missing root"))?;
         let dest = parts
             .next()
-            .ok_or(Error::new(ErrorKind::Other, "Missing dest"))?;
-        let fs = parts
-            .next()
-            .ok_or(Error::new(ErrorKind::Other, "Missing fs"))?;
+            .ok_or(Error::other("Missing dest"))?;
         let options = parts
             .next()
-            .ok_or(Error::new(ErrorKind::Other, "Missing options"))?;
-        let dump = parts
+            .ok_or(Error::other("Missing options"))?;
+
+        // A variable number of optional fields is terminated by a hyphen.
+        if !parts.any(|part| part == "-") {
+            return Err(Error::other("Missing separator"));
+        }
+
+        let fs = parts
             .next()
-            .ok_or(Error::new(ErrorKind::Other, "Missing dump"))?;
-        let pass = parts
+            .ok_or(Error::other("Missing fs"))?;
+        let source = parts
             .next()
-            .ok_or(Error::new(ErrorKind::Other, "Missing pass"))?;
+            .ok_or(Error::other("Missing source"))?;
 
         Ok(Mount {
-            source:  Self::parse_value(&source)?,
-            dest:    Self::parse_value(&dest)?,
-            fs:      Self::parse_value(&fs)?,
-            options: Self::parse_value(&options)?,
-            dump:    Self::parse_value(&dump)?,
-            pass:    Self::parse_value(&pass)?,
+            device:  Self::parse_device(device)?,
+            root:    Self::parse_value(root)?,
+            source:  Self::parse_value(source)?,
+            dest:    Self::parse_value(dest)?,
+            fs:      Self::parse_value(fs)?,
+            options: Self::parse_value(options)?,
         })
     }
 
-    pub fn all() -> Result<Vec<Mount>> {
+    /// Parses a device number, written as `major:minor`.
+    fn parse_device(value: &str) -> Result<u64> {
+        let mut numbers = value.splitn(2, ':').map(|number| number.parse::<u32>());
+        match (numbers.next(), numbers.next()) {
+            (Some(Ok(major)), Some(Ok(minor))) => Ok(libc::makedev(major, minor)),
+            _ => Err(Error::other("Invalid device number")),
+        }
+    }
+
+    /// The block device of the filesystem. Filesystems such as btrfs report
+    /// an anonymous device, so the source of their mount is used instead.
+    pub fn block_device(&self) -> u64 {
+        if libc::major(self.device) != 0 {
+            return self.device;
+        }
+
+        match fs::metadata(&self.source) {
+            Ok(ref metadata) if metadata.file_type().is_block_device() => metadata.rdev(),
+            _ => self.device,
+        }
+    }
+
+    pub fn all() -> Result<Vec<Mount>> { Self::read("/proc/self/mountinfo") }
+
+    /// Reads the mounts of a mount namespace, from a mountinfo file.
+    pub(crate) fn read<P: AsRef<Path>>(path: P) -> Result<Vec<Mount>> {
         let mut ret = Vec::new();
 
-        let file = BufReader::new(File::open("/proc/self/mounts")?);
+        let file = BufReader::new(File::open(path)?);
         for line_res in file.lines() {
             let line = line_res?;
             ret.push(Self::parse_line(&line)?);
@@ -85,4 +125,163 @@ impl Mount {
 
         Ok(ret)
     }
+
+    /// Finds every mount of the block device at `disk`, of its partitions,
+    /// and of the devices which are built upon them, such as device-mapper
+    /// targets.
+    pub fn of_disk<P: AsRef<Path>>(mounts: &[Mount], disk: P) -> Result<Vec<&Mount>> {
+        let devices = disk_devices(disk)?;
+        Ok(mounts
+            .iter()
+            .filter(|mount| devices.contains(&mount.block_device()))
+            .collect())
+    }
+}
+
+/// Adds the anonymous devices of the filesystems which are mounted from the
+/// `devices`, such as those of btrfs, so that the files within them are
+/// found to be on the devices as well.
+pub(crate) fn with_filesystems(mounts: &[Mount], devices: &mut Vec<u64>) {
+    for mount in mounts {
+        if libc::major(mount.device) == 0
+            && !devices.contains(&mount.device)
+            && devices.contains(&mount.block_device())
+        {
+            devices.push(mount.device);
+        }
+    }
+}
+
+/// The device numbers of the block device at `disk`, of its partitions, and
+/// of the devices which are built upon them.
+pub(crate) fn disk_devices<P: AsRef<Path>>(disk: P) -> Result<Vec<u64>> {
+    let metadata = fs::metadata(disk)?;
+    if !metadata.file_type().is_block_device() {
+        return Ok(Vec::new());
+    }
+
+    let rdev = metadata.rdev();
+    let sys = format!("/sys/dev/block/{}:{}", libc::major(rdev), libc::minor(rdev));
+    let mut devices = vec![rdev];
+    collect_devices(&fs::canonicalize(sys)?, &mut devices, 0);
+    Ok(devices)
+}
+
+/// Collects the device numbers of the partitions and holders of a device,
+/// from its directory within sysfs.
+fn collect_devices(sys: &Path, devices: &mut Vec<u64>, depth: u8) {
+    // Stacks of devices are shallow, so a deep one can only be a loop.
+    if depth > 16 {
+        return;
+    }
+
+    let partitions = fs::read_dir(sys)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.join("partition").exists());
+
+    let holders = fs::read_dir(sys.join("holders"))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| fs::canonicalize(entry.path()).ok());
+
+    for child in partitions.chain(holders).collect::<Vec<PathBuf>>() {
+        let device = fs::read_to_string(child.join("dev"))
+            .ok()
+            .and_then(|device| Mount::parse_device(device.trim()).ok());
+        if let Some(device) = device {
+            if !devices.contains(&device) {
+                devices.push(device);
+                collect_devices(&child, devices, depth + 1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn optional_fields() {
+        let line = "97 29 8:17 / /media/user/My\\040Drive rw,nosuid,nodev shared:51 master:3 \
+                    - vfat /dev/sdb1 rw,fmask=0022";
+        let mount = Mount::parse_line(line).unwrap();
+        assert_eq!(mount.device, libc::makedev(8, 17));
+        assert_eq!(mount.root, OsString::from("/"));
+        assert_eq!(mount.dest, OsString::from("/media/user/My Drive"));
+        assert_eq!(mount.options, OsString::from("rw,nosuid,nodev"));
+        assert_eq!(mount.fs, OsString::from("vfat"));
+        assert_eq!(mount.source, OsString::from("/dev/sdb1"));
+
+        // Bind mounts of a directory within the filesystem, with no optional fields.
+        let line = "36 35 259:3 /home/user/a\\011b\\134c /mnt rw - ext4 /dev/nvme0n1p3 rw";
+        let mount = Mount::parse_line(line).unwrap();
+        assert_eq!(mount.device, libc::makedev(259, 3));
+        assert_eq!(mount.root, OsString::from("/home/user/a\tb\\c"));
+        assert_eq!(mount.dest, OsString::from("/mnt"));
+        assert_eq!(mount.source, OsString::from("/dev/nvme0n1p3"));
+    }
+
+    #[test]
+    fn anonymous_devices() {
+        let line = "64 1 0:31 /@home /home rw,relatime shared:30 \
+                    - btrfs /dev/sdb2 rw,subvol=/@home";
+        let mount = Mount::parse_line(line).unwrap();
+        assert_eq!(mount.device, libc::makedev(0, 31));
+        assert_eq!(mount.root, OsString::from("/@home"));
+        assert_eq!(mount.fs, OsString::from("btrfs"));
+
+        // Sources which are not block devices leave the device as it was.
+        let line = "65 1 0:32 / /tmp rw - tmpfs tmpfs rw";
+        let tmpfs = Mount::parse_line(line).unwrap();
+        assert_eq!(tmpfs.block_device(), libc::makedev(0, 32));
+
+        // Otherwise, the filesystem is on the device of its source.
+        let block = fs::read_dir("/dev")
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.file_type().is_ok_and(|kind| kind.is_block_device()));
+        if let Some(block) = block {
+            let rdev = block.metadata().unwrap().rdev();
+            let mount = Mount { source: block.path().into_os_string(), ..mount };
+            assert_eq!(mount.block_device(), rdev);
+
+            let mut devices = vec![rdev];
+            with_filesystems(&[mount, tmpfs], &mut devices);
+            assert_eq!(devices, vec![rdev, libc::makedev(0, 31)]);
+        }
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(Mount::parse_value("a\\040b\\012").unwrap(), OsString::from("a b\n"));
+        assert_eq!(
+            Mount::parse_value("\\351t\\351").unwrap(),
+            OsString::from_vec(vec![0xE9, b't', 0xE9])
+        );
+        assert!(Mount::parse_value("a\\04").is_err());
+        assert!(Mount::parse_value("a\\09x").is_err());
+    }
+
+    #[test]
+    fn devices() {
+        assert_eq!(Mount::parse_device("0:52").unwrap(), libc::makedev(0, 52));
+        assert_eq!(Mount::parse_device("8:0").unwrap(), libc::makedev(8, 0));
+        assert!(Mount::parse_device("8").is_err());
+        assert!(Mount::parse_device("8:").is_err());
+        assert!(Mount::parse_device("sda:1").is_err());
+    }
+
+    #[test]
+    fn invalid_lines() {
+        assert!(Mount::parse_line("").is_err());
+        assert!(Mount::parse_line("97 29 8:17 / /mnt rw shared:51 ext4 /dev/sdb1 rw").is_err());
+        assert!(Mount::parse_line("97 29 8:17 / /mnt rw -").is_err());
+        assert!(Mount::parse_line("97 29 8 / /mnt rw - ext4 /dev/sdb1 rw").is_err());
+    }
 }
@@ -0,0 +1,101 @@
+//! Finds the processes which are using a disk, so that users may learn why
+//! the disk is busy.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use super::mount::{disk_devices, with_filesystems, Mount};
+
+/// A process which holds a file, directory, or mount on a disk.
+#[derive(Clone, Debug)]
+pub struct Process {
+    pub pid:     u32,
+    pub command: String,
+    /// The file, directory, or mount point on the disk which the process holds.
+    pub path:    PathBuf,
+}
+
+impl Process {
+    /// Finds the processes which have files open on the block device at `disk`,
+    /// on its partitions, or on the filesystems within them, as well as those
+    /// which are within them, and the mount namespaces which have them mounted.
+    ///
+    /// Processes that cannot be inspected, such as those of other users when
+    /// not running as root, are skipped.
+    pub fn holding<P: AsRef<Path>>(disk: P) -> io::Result<Vec<Process>> {
+        let mut devices = disk_devices(disk)?;
+        with_filesystems(&Mount::all().unwrap_or_default(), &mut devices);
+        let own_namespace = fs::read_link("/proc/self/ns/mnt").ok();
+        let mut namespaces = HashSet::new();
+        let mut processes = Vec::new();
+
+        for entry in fs::read_dir("/proc")? {
+            let entry = entry?;
+            let pid = match entry.file_name().to_str().and_then(|pid| pid.parse::<u32>().ok()) {
+                Some(pid) if pid != ::std::process::id() => pid,
+                _ => continue,
+            };
+
+            let proc_dir = entry.path();
+            let command = fs::read_to_string(proc_dir.join("comm"))
+                .map(|command| command.trim().to_owned())
+                .unwrap_or_default();
+
+            let mut paths = Vec::new();
+            let links = ["cwd", "root", "exe"].iter().map(|link| proc_dir.join(link));
+            let fds = fs::read_dir(proc_dir.join("fd"))
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path());
+
+            for link in links.chain(fds) {
+                if is_on(&link, &devices) {
+                    if let Ok(path) = fs::read_link(&link) {
+                        if !paths.contains(&path) {
+                            paths.push(path);
+                        }
+                    }
+                }
+            }
+
+            // Each mount namespace is only reported once, by its first process.
+            if let Ok(namespace) = fs::read_link(proc_dir.join("ns/mnt")) {
+                if Some(&namespace) != own_namespace.as_ref() && namespaces.insert(namespace) {
+                    let mounts = Mount::read(proc_dir.join("mountinfo")).unwrap_or_default();
+                    let on_disk = mounts
+                        .iter()
+                        .filter(|mount| devices.contains(&mount.block_device()));
+                    for mount in on_disk {
+                        paths.push(PathBuf::from(&mount.dest));
+                    }
+                }
+            }
+
+            for path in paths {
+                processes.push(Process {
+                    pid,
+                    command: command.clone(),
+                    path,
+                });
+            }
+        }
+
+        processes.sort_by_key(|process| process.pid);
+        Ok(processes)
+    }
+}
+
+/// Whether the file at `path` is one of the devices, or is on one of them.
+fn is_on(path: &Path, devices: &[u64]) -> bool {
+    match fs::metadata(path) {
+        Ok(ref metadata) if metadata.file_type().is_block_device() => {
+            devices.contains(&metadata.rdev())
+        }
+        Ok(metadata) => devices.contains(&metadata.dev()),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,35 @@
+//! Reports the progress of a flash, phase by phase, so that frontends need not
+//! track which phase a flash is in by themselves.
+
+/// An event in the flash of a single disk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FlashEvent {
+    /// The flash has begun.
+    Started,
+    /// The capacity of the disk is being probed, before anything is written.
+    Probing,
+    /// This many bytes of the image have been written.
+    Writing { bytes: u64 },
+    /// Everything has been written, and is being synced to the disk.
+    Syncing,
+    /// This many bytes of the disk have been read back and verified.
+    Verifying { bytes: u64 },
+    /// The flash has completed. No more events follow.
+    Finished,
+    /// The flash was cancelled. No more events follow.
+    Cancelled,
+    /// The flash has failed. No more events follow.
+    Failed { error: String },
+}
+
+/// Receives the events of a flash as it progresses.
+///
+/// Closures which take a `FlashEvent` implement this, so that events may be
+/// handled in place, or sent through a channel to another thread.
+pub trait FlashProgress {
+    fn event(&mut self, event: FlashEvent);
+}
+
+impl<F: FnMut(FlashEvent)> FlashProgress for F {
+    fn event(&mut self, event: FlashEvent) { self(event) }
+}
@@ -0,0 +1,217 @@
+//! Records how far each flash has progressed, so that a flash which was
+//! interrupted may be resumed, rather than started again.
+
+use sha2::{Digest, Sha256};
+use std::cmp;
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use super::image::Image;
+
+/// How much of the start and the end of the image file is hashed.
+const FINGERPRINT_SAMPLE: u64 = 1024 * 1024;
+
+/// Resumes flashes of an image, from records which are kept for each device.
+///
+/// Each record is keyed by a hash of the image, and by the serial of the
+/// device, so that a record is never applied to another image or device.
+/// Devices without a serial cannot be told apart, and so are never resumed.
+#[derive(Clone, Debug)]
+pub struct Resume {
+    dir:   PathBuf,
+    image: Vec<u8>,
+}
+
+impl Resume {
+    /// Keeps records within `dir`, for flashes of the given image.
+    ///
+    /// Rather than reading the whole image, the hash covers the size, the
+    /// modification time, and the start and the end of the image file, as
+    /// well as the size of the image within, if known, and the entry it was
+    /// read from.
+    pub fn new<P: AsRef<Path>>(dir: P, image: &Image) -> io::Result<Resume> {
+        let file = File::open(image.get_path())?;
+        let metadata = file.metadata()?;
+        let len = metadata.len();
+
+        let mut hasher = Sha256::new();
+        hasher.update(len.to_le_bytes());
+        hasher.update(metadata.mtime().to_le_bytes());
+        hasher.update(metadata.mtime_nsec().to_le_bytes());
+        if let Some(size) = image.get_size() {
+            hasher.update(size.to_le_bytes());
+        }
+        hasher.update(image.get_entry().unwrap_or("").as_bytes());
+
+        let sample = cmp::min(len, FINGERPRINT_SAMPLE);
+        let mut buffer = vec![0; sample as usize];
+        file.read_exact_at(&mut buffer, 0)?;
+        hasher.update(&buffer);
+        file.read_exact_at(&mut buffer, len - sample)?;
+        hasher.update(&buffer);
+
+        Ok(Resume {
+            dir:   dir.as_ref().to_path_buf(),
+            image: hasher.finalize().to_vec(),
+        })
+    }
+
+    /// The directory in which records are kept by default, which is within
+    /// `$XDG_STATE_HOME`, or `~/.local/state` if it is not set.
+    pub fn default_dir() -> PathBuf {
+        let state = env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+            .unwrap_or_else(|| PathBuf::from("/var/lib"));
+        state.join("popsicle/resume")
+    }
+
+    /// The offset which the flash to the device with `serial` reached, before
+    /// it was interrupted. Everything before the offset was synced to the device.
+    pub fn offset(&self, serial: &str) -> u64 {
+        self.record(serial)
+            .and_then(|record| fs::read_to_string(record).ok())
+            .and_then(|offset| offset.trim().parse::<u64>().ok())
+            .unwrap_or(0)
+    }
+
+    /// Records that everything before `offset` was synced to the device.
+    pub(crate) fn save(&self, serial: &str, offset: u64) -> io::Result<()> {
+        let record = match self.record(serial) {
+            Some(record) => record,
+            None => return Ok(()),
+        };
+
+        // The record is replaced atomically, so that it is never left half-written.
+        fs::create_dir_all(&self.dir)?;
+        let temporary = record.with_extension("tmp");
+        fs::write(&temporary, offset.to_string())?;
+        fs::rename(temporary, record)
+    }
+
+    /// Removes the record, once the flash has completed.
+    pub(crate) fn clear(&self, serial: &str) -> io::Result<()> {
+        match self.record(serial).map(fs::remove_file) {
+            Some(Err(ref why)) if why.kind() == io::ErrorKind::NotFound => Ok(()),
+            Some(result) => result,
+            None => Ok(()),
+        }
+    }
+
+    fn record(&self, serial: &str) -> Option<PathBuf> {
+        if serial.is_empty() {
+            return None;
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(&self.image);
+        hasher.update(serial.as_bytes());
+        let key: String = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Some(self.dir.join(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    /// A directory holding an image, and the records kept for it.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = env::temp_dir().join(format!("popsicle-resume-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn image(&self, data: &[u8]) -> Image {
+            let path = self.0.join("image.img");
+            fs::write(&path, data).unwrap();
+            Image::new(path).unwrap()
+        }
+
+        fn resume(&self, image: &Image) -> Resume {
+            Resume::new(self.0.join("records"), image).unwrap()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) { let _ = fs::remove_dir_all(&self.0); }
+    }
+
+    #[test]
+    fn records() {
+        let dir = TempDir::new("records");
+        let resume = dir.resume(&dir.image(&[1; 4096]));
+        assert_eq!(resume.offset("SERIAL"), 0);
+
+        resume.save("SERIAL", 2048).unwrap();
+        assert_eq!(resume.offset("SERIAL"), 2048);
+        resume.save("SERIAL", 3072).unwrap();
+        assert_eq!(resume.offset("SERIAL"), 3072);
+
+        // Records of other devices are kept apart.
+        assert_eq!(resume.offset("OTHER"), 0);
+        resume.save("OTHER", 1024).unwrap();
+        assert_eq!(resume.offset("SERIAL"), 3072);
+        assert_eq!(resume.offset("OTHER"), 1024);
+
+        resume.clear("SERIAL").unwrap();
+        assert_eq!(resume.offset("SERIAL"), 0);
+        assert_eq!(resume.offset("OTHER"), 1024);
+
+        // Clearing a record which does not exist is not an error.
+        resume.clear("SERIAL").unwrap();
+    }
+
+    #[test]
+    fn without_serial() {
+        let dir = TempDir::new("serial");
+        let resume = dir.resume(&dir.image(&[1; 4096]));
+        assert!(resume.record("").is_none());
+
+        resume.save("", 2048).unwrap();
+        assert_eq!(resume.offset(""), 0);
+        assert!(!dir.0.join("records").exists());
+        resume.clear("").unwrap();
+    }
+
+    #[test]
+    fn changed_image() {
+        let dir = TempDir::new("changed");
+        let image = dir.image(&[1; 4096]);
+        let resume = dir.resume(&image);
+        resume.save("SERIAL", 2048).unwrap();
+
+        // The same image is fingerprinted the same way.
+        assert_eq!(dir.resume(&image).offset("SERIAL"), 2048);
+
+        // Another image, even of the same size, has records of its own.
+        let changed = dir.resume(&dir.image(&[2; 4096]));
+        assert_ne!(changed.image, resume.image);
+        assert_eq!(changed.offset("SERIAL"), 0);
+
+        let resized = dir.resume(&dir.image(&[1; 8192]));
+        assert_eq!(resized.offset("SERIAL"), 0);
+    }
+
+    #[test]
+    fn corrupt_record() {
+        let dir = TempDir::new("corrupt");
+        let resume = dir.resume(&dir.image(&[1; 4096]));
+        resume.save("SERIAL", 2048).unwrap();
+        fs::write(resume.record("SERIAL").unwrap(), "not an offset").unwrap();
+        assert_eq!(resume.offset("SERIAL"), 0);
+    }
+}
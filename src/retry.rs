@@ -0,0 +1,66 @@
+//! Retries writes which fail, as flaky hubs and cables often cause transient
+//! errors, and records where they occurred.
+
+use std::cmp;
+use std::time::Duration;
+
+/// How writes which fail are retried.
+#[derive(Clone, Debug)]
+pub struct RetryOptions {
+    /// How many more times to try a write which failed. Without any retries,
+    /// the first error ends the flash.
+    pub attempts: u32,
+    /// How long to wait before the first retry. The wait doubles with each
+    /// retry which follows.
+    pub backoff: Duration,
+}
+
+impl RetryOptions {
+    /// How long to wait before the given retry, counting from one.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        self.backoff * (1 << cmp::min(attempt.saturating_sub(1), 5))
+    }
+}
+
+impl Default for RetryOptions {
+    fn default() -> RetryOptions {
+        RetryOptions {
+            attempts: 3,
+            backoff:  Duration::from_millis(250),
+        }
+    }
+}
+
+/// A region of the disk which could not be written at the first attempt.
+///
+/// Regions which were written after a retry point to a flaky connection,
+/// whereas regions which could never be written point to a failing drive.
+#[derive(Clone, Debug)]
+pub struct BadBlock {
+    pub offset:    u64,
+    pub len:       u64,
+    /// How many times the write was retried.
+    pub retries:   u32,
+    /// Whether the region was written in the end.
+    pub recovered: bool,
+    /// The last error which the write failed with.
+    pub error:     String,
+}
+
+/// The retries of a flash, and the regions which needed them.
+pub(crate) struct Retrying<'a> {
+    pub options: &'a RetryOptions,
+    pub blocks:  Vec<BadBlock>,
+}
+
+impl<'a> Retrying<'a> {
+    pub fn new(options: &'a RetryOptions) -> Retrying<'a> {
+        Retrying {
+            options,
+            blocks: Vec::new(),
+        }
+    }
+
+    /// How many regions could not be written at all.
+    pub fn failed(&self) -> usize { self.blocks.iter().filter(|block| !block.recovered).count() }
+}
@@ -0,0 +1,219 @@
+use std::mem;
+use std::ops::Deref;
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::Arc;
+
+pub(crate) const BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// Chunk buffers are over-allocated by this much, so that their data can
+/// begin at an address which is aligned for direct I/O.
+pub(crate) const BUFFER_ALIGN: usize = 4096;
+
+/// The number of chunk buffers that are shared between the image reader and
+/// all of the disks that the image is being written to.
+pub(crate) const POOL_SIZE: usize = 8;
+
+/// A block of image data which is to be written at `offset` on each disk.
+///
+/// Chunks are shared between every target through an `Arc`. Once the last
+/// reference has been dropped, the buffer is handed back to the reader.
+pub struct Chunk {
+    offset: u64,
+    data:   Vec<u8>,
+    len:    usize,
+    pool:   SyncSender<Vec<u8>>,
+}
+
+impl Chunk {
+    pub(crate) fn new(
+        offset: u64,
+        data: Vec<u8>,
+        len: usize,
+        pool: SyncSender<Vec<u8>>,
+    ) -> Arc<Chunk> {
+        Arc::new(Chunk {
+            offset,
+            data,
+            len,
+            pool,
+        })
+    }
+
+    /// The position in the image where this chunk begins.
+    pub fn offset(&self) -> u64 { self.offset }
+}
+
+impl Deref for Chunk {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        let start = self.data.as_ptr().align_offset(BUFFER_ALIGN);
+        &self.data[start..start + self.len]
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) { let _ = self.pool.send(mem::take(&mut self.data)); }
+}
+
+/// Returns `len` bytes of the buffer, starting from the first address within
+/// it that is a multiple of `align`.
+pub(crate) fn aligned(buffer: &mut [u8], align: usize, len: usize) -> &mut [u8] {
+    let start = buffer.as_ptr().align_offset(align);
+    &mut buffer[start..start + len]
+}
+
+pub(crate) enum Message {
+    Chunk(Arc<Chunk>),
+    End,
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "image stream ended before the image was fully read")]
+pub struct StreamInterrupted;
+
+/// The receiving end of an image that is being read by `Image::stream`.
+pub struct ImageStream {
+    receiver: Receiver<Message>,
+    size:     Option<u64>,
+}
+
+impl ImageStream {
+    pub(crate) fn new(receiver: Receiver<Message>, size: Option<u64>) -> ImageStream {
+        ImageStream { receiver, size }
+    }
+
+    /// The total size of the image being streamed, in bytes, if it is known
+    /// before the image has been read.
+    pub fn size(&self) -> Option<u64> { self.size }
+
+    /// Waits for the next chunk of the image. Returns `None` after the last
+    /// chunk, or an error if the reader stopped before reaching the end.
+    pub fn next_chunk(&self) -> Result<Option<Arc<Chunk>>, StreamInterrupted> {
+        match self.receiver.recv() {
+            Ok(Message::Chunk(chunk)) => Ok(Some(chunk)),
+            Ok(Message::End) => Ok(None),
+            Err(_) => Err(StreamInterrupted),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Image;
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::thread;
+    use std::time::Duration;
+    use std::{env, fs, process};
+
+    /// An image of several full chunks, which ends with a partial chunk.
+    fn open(name: &str) -> (Image, Vec<u8>) {
+        let data: Vec<u8> = (0..(POOL_SIZE + 2) * BUFFER_SIZE + 12345)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let path = env::temp_dir().join(format!("popsicle-stream-{}-{}", name, process::id()));
+        fs::write(&path, &data).unwrap();
+        let image = Image::new(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        (image, data)
+    }
+
+    /// Reads the rest of the stream onto `data`, checking that each chunk begins
+    /// where the last one ended, and that each is aligned for direct I/O.
+    fn read(stream: &ImageStream, mut data: Vec<u8>, delay: Duration) -> Vec<u8> {
+        while let Some(chunk) = stream.next_chunk().unwrap() {
+            assert_eq!(chunk.offset(), data.len() as u64);
+            assert_eq!(chunk.as_ptr() as usize % BUFFER_ALIGN, 0);
+            data.extend_from_slice(&chunk);
+            thread::sleep(delay);
+        }
+        data
+    }
+
+    #[test]
+    fn identical_targets() {
+        let (image, data) = open("identical");
+        let (reader, streams) = image.stream(3);
+        assert!(streams.iter().all(|stream| stream.size() == Some(data.len() as u64)));
+
+        // One of the targets is far slower than the others.
+        let targets: Vec<_> = streams
+            .into_iter()
+            .enumerate()
+            .map(|(id, stream)| {
+                let delay = Duration::from_millis(if id == 0 { 20 } else { 0 });
+                thread::spawn(move || read(&stream, Vec::new(), delay))
+            })
+            .collect();
+
+        for target in targets {
+            assert!(target.join().unwrap() == data);
+        }
+        reader.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn bounded_pool() {
+        let (image, data) = open("pool");
+        let (reader, mut streams) = image.stream(1);
+        let stream = streams.pop().unwrap();
+        let wait = Duration::from_millis(200);
+
+        // The reader stalls once every buffer in the pool is held.
+        let mut held = Vec::new();
+        for _ in 0..POOL_SIZE {
+            match stream.receiver.recv_timeout(wait) {
+                Ok(Message::Chunk(chunk)) => held.push(chunk),
+                _ => panic!("expected a chunk"),
+            }
+        }
+        match stream.receiver.recv_timeout(wait) {
+            Err(RecvTimeoutError::Timeout) => (),
+            _ => panic!("read more chunks than there are buffers"),
+        }
+
+        // Releasing a buffer lets the reader continue.
+        let mut received = Vec::new();
+        received.extend_from_slice(&held.remove(0));
+        match stream.receiver.recv_timeout(Duration::from_secs(10)) {
+            Ok(Message::Chunk(chunk)) => held.push(chunk),
+            _ => panic!("expected a chunk once a buffer was released"),
+        }
+
+        for chunk in held.drain(..) {
+            received.extend_from_slice(&chunk);
+        }
+        let received = read(&stream, received, Duration::from_millis(0));
+        assert!(received == data);
+        reader.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn dropped_targets() {
+        // A target which is dropped does not hold back the others.
+        let (image, data) = open("dropped");
+        let (reader, mut streams) = image.stream(2);
+        drop(streams.pop());
+        assert!(read(&streams[0], Vec::new(), Duration::from_millis(0)) == data);
+        reader.join().unwrap().unwrap();
+
+        // The reader stops once every target is dropped, even midway.
+        let (image, _) = open("abandoned");
+        let (reader, streams) = image.stream(2);
+        let first = streams[0].next_chunk().unwrap().unwrap();
+        drop(streams);
+        drop(first);
+        reader.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn alignment() {
+        let mut buffer = vec![0; 3 * BUFFER_ALIGN];
+        for &len in &[0, 1, BUFFER_ALIGN, 2 * BUFFER_ALIGN] {
+            let slice = aligned(&mut buffer[1..], BUFFER_ALIGN, len);
+            assert_eq!(slice.as_ptr() as usize % BUFFER_ALIGN, 0);
+            assert_eq!(slice.len(), len);
+        }
+    }
+}
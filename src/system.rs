@@ -0,0 +1,178 @@
+//! Finds the disks which the running system depends upon, so that they are
+//! never flashed by mistake.
+
+use libc;
+use std::fs::{self, canonicalize};
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use super::mount::Mount;
+
+/// The mount points which hold the operating system and the user's data.
+const SYSTEM_MOUNTS: &[&str] = &["/", "/boot", "/boot/efi", "/home", "/usr", "/var"];
+
+/// The whole disks which back the running system.
+///
+/// Each device is followed through sysfs to the disks beneath it, so that
+/// disks are found through partitions, device-mapper, LVM, md, and LUKS.
+/// A `SystemDisks::default()` contains no disks, and so allows every disk
+/// to be flashed.
+#[derive(Clone, Debug, Default)]
+pub struct SystemDisks {
+    /// The sysfs directory of each disk, and a description of what it holds.
+    disks: Vec<(PathBuf, String)>,
+}
+
+impl SystemDisks {
+    /// Finds the disks backing the system's mount points, active swap, and
+    /// the image which is being flashed.
+    pub fn detect<P: AsRef<Path>>(mounts: &[Mount], image: P) -> io::Result<SystemDisks> {
+        let mut system = SystemDisks::default();
+
+        for &mount_point in SYSTEM_MOUNTS {
+            if let Some(device) = mount_device(mounts, Path::new(mount_point)) {
+                system.add(device, &format!("the filesystem at '{}'", mount_point));
+            }
+        }
+
+        for swap in swaps()? {
+            if let Ok(metadata) = fs::metadata(&swap) {
+                let device = if metadata.file_type().is_block_device() {
+                    metadata.rdev()
+                } else {
+                    metadata.dev()
+                };
+                system.add(device, &format!("the active swap at '{}'", swap.display()));
+            }
+        }
+
+        let image = canonicalize(image)?;
+        let metadata = fs::metadata(&image)?;
+        if metadata.file_type().is_block_device() {
+            system.add(metadata.rdev(), "the image being flashed");
+        } else {
+            let device = containing_mount(mounts, &image)
+                .and_then(|mount_point| mount_device(mounts, mount_point))
+                .unwrap_or_else(|| metadata.dev());
+            system.add(device, "the image being flashed");
+        }
+
+        Ok(system)
+    }
+
+    /// Describes what the system keeps on the block device at `path`, if the
+    /// device shares a disk with the running system.
+    pub fn holds<P: AsRef<Path>>(&self, path: P) -> Option<&str> {
+        let metadata = fs::metadata(path).ok()?;
+        if !metadata.file_type().is_block_device() {
+            return None;
+        }
+
+        let disks = whole_disks(metadata.rdev());
+        self.disks
+            .iter()
+            .find(|(disk, _)| disks.contains(disk))
+            .map(|(_, holds)| holds.as_str())
+    }
+
+    fn add(&mut self, device: u64, holds: &str) {
+        for disk in whole_disks(device) {
+            if !self.disks.iter().any(|(known, _)| *known == disk) {
+                self.disks.push((disk, holds.to_owned()));
+            }
+        }
+    }
+}
+
+/// The device of the filesystem at `path`. Filesystems such as btrfs report
+/// an anonymous device, so the source of their mount is used instead.
+fn mount_device(mounts: &[Mount], path: &Path) -> Option<u64> {
+    let device = fs::metadata(path).ok()?.dev();
+    if libc::major(device) != 0 {
+        return Some(device);
+    }
+
+    let mount = mounts.iter().rev().find(|mount| Path::new(&mount.dest) == path)?;
+    let metadata = fs::metadata(&mount.source).ok()?;
+    if metadata.file_type().is_block_device() {
+        Some(metadata.rdev())
+    } else {
+        None
+    }
+}
+
+/// The mount point of the filesystem which holds `path`.
+fn containing_mount<'a>(mounts: &'a [Mount], path: &Path) -> Option<&'a Path> {
+    mounts
+        .iter()
+        .map(|mount| Path::new(&mount.dest))
+        .filter(|mount_point| path.starts_with(mount_point))
+        .max_by_key(|mount_point| mount_point.as_os_str().len())
+}
+
+/// The paths of the swap files and partitions which are in use.
+pub(crate) fn swaps() -> io::Result<Vec<PathBuf>> {
+    let file = match fs::File::open("/proc/swaps") {
+        Ok(file) => file,
+        Err(ref why) if why.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(why) => return Err(why),
+    };
+
+    let mut swaps = Vec::new();
+    for line in BufReader::new(file).lines().skip(1) {
+        let line = line?;
+        if let Some(name) = line.split_whitespace().next() {
+            swaps.push(PathBuf::from(Mount::parse_value(name)?));
+        }
+    }
+
+    Ok(swaps)
+}
+
+/// Follows a block device down to the whole disks that it is built upon.
+fn whole_disks(device: u64) -> Vec<PathBuf> {
+    let sys = format!("/sys/dev/block/{}:{}", libc::major(device), libc::minor(device));
+    let mut disks = Vec::new();
+    if let Ok(sys) = canonicalize(sys) {
+        collect_disks(&sys, &mut disks, 0);
+    }
+    disks
+}
+
+fn collect_disks(sys: &Path, disks: &mut Vec<PathBuf>, depth: u8) {
+    // Stacks of devices are shallow, so a deep one can only be a loop.
+    if depth > 16 {
+        return;
+    }
+
+    let slaves = fs::read_dir(sys.join("slaves"))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| canonicalize(entry.path()).ok())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    if !slaves.is_empty() {
+        for slave in slaves {
+            collect_disks(&slave, disks, depth + 1);
+        }
+        return;
+    }
+
+    // Partitions are found beneath the directory of their disk.
+    let disk = if sys.join("partition").exists() {
+        match sys.parent() {
+            Some(parent) => parent.to_path_buf(),
+            None => return,
+        }
+    } else {
+        sys.to_path_buf()
+    };
+
+    if !disks.contains(&disk) {
+        disks.push(disk);
+    }
+}
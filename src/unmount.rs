@@ -0,0 +1,153 @@
+//! Releases the filesystems and swap areas on a disk, through system calls
+//! rather than external commands, so that disks may be flashed from rescue
+//! environments which lack util-linux.
+
+use libc;
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use super::mount::{disk_devices, with_filesystems, Mount};
+use super::system::swaps;
+use super::DiskError;
+
+/// How long to wait before trying to unmount a busy filesystem again.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// How the filesystems on a disk are unmounted before it is flashed.
+#[derive(Clone, Debug)]
+pub struct UnmountOptions {
+    /// Detach filesystems at once, leaving the kernel to clean them up once
+    /// they are no longer busy.
+    pub lazy: bool,
+    /// Abort pending requests, for filesystems which are unreachable.
+    pub force: bool,
+    /// How many more times to try unmounting a filesystem which is busy.
+    pub retries: u32,
+}
+
+impl Default for UnmountOptions {
+    fn default() -> UnmountOptions {
+        UnmountOptions {
+            lazy:    false,
+            force:   false,
+            retries: 3,
+        }
+    }
+}
+
+/// What was released from a disk so that it could be flashed.
+#[derive(Clone, Debug, Default)]
+pub struct Released {
+    /// The swap partitions and files which were deactivated.
+    pub swaps: Vec<PathBuf>,
+    /// The source and destination of each mount which was unmounted.
+    pub mounts: Vec<(OsString, OsString)>,
+}
+
+impl Released {
+    pub fn is_empty(&self) -> bool { self.swaps.is_empty() && self.mounts.is_empty() }
+}
+
+/// Deactivates the swap on the block device at `disk`, and then unmounts its
+/// filesystems. Swap files are included, as they keep their filesystem busy.
+pub fn release_disk<P: AsRef<Path>>(
+    disk: P,
+    mounts: &[Mount],
+    options: &UnmountOptions,
+) -> Result<Released, DiskError> {
+    let disk = disk.as_ref();
+    let metadata_error = |why| DiskError::Metadata {
+        arg: disk.to_string_lossy().into_owned(),
+        why,
+    };
+
+    let mut devices = disk_devices(disk).map_err(metadata_error)?;
+    with_filesystems(mounts, &mut devices);
+    let mut released = Released::default();
+
+    for swap in swaps().map_err(metadata_error)? {
+        let on_disk = fs::metadata(&swap)
+            .map(|metadata| {
+                let device = if metadata.file_type().is_block_device() {
+                    metadata.rdev()
+                } else {
+                    metadata.dev()
+                };
+                devices.contains(&device)
+            })
+            .unwrap_or(false);
+
+        if on_disk {
+            swapoff(&swap).map_err(|why| DiskError::Swapoff {
+                disk: disk.to_string_lossy().into_owned(),
+                path: swap.clone(),
+                why,
+            })?;
+            released.swaps.push(swap);
+        }
+    }
+
+    // Mounts are listed before those within them, which must be unmounted first.
+    let on_disk = mounts.iter().filter(|mount| devices.contains(&mount.block_device()));
+    for mount in on_disk.rev() {
+        unmount(Path::new(&mount.dest), options).map_err(|why| DiskError::Unmount {
+            disk: disk.to_string_lossy().into_owned(),
+            path: mount.dest.clone(),
+            why,
+        })?;
+        released
+            .mounts
+            .push((mount.source.clone(), mount.dest.clone()));
+    }
+
+    Ok(released)
+}
+
+fn unmount(path: &Path, options: &UnmountOptions) -> io::Result<()> {
+    let path = cstring(path)?;
+    let mut flags = 0;
+    if options.lazy {
+        flags |= libc::MNT_DETACH;
+    }
+    if options.force {
+        flags |= libc::MNT_FORCE;
+    }
+
+    let mut retries = options.retries;
+    loop {
+        if unsafe { libc::umount2(path.as_ptr(), flags) } == 0 {
+            return Ok(());
+        }
+
+        let why = io::Error::last_os_error();
+        match why.raw_os_error() {
+            Some(libc::EBUSY) if retries != 0 => {
+                retries -= 1;
+                thread::sleep(RETRY_DELAY);
+            }
+            // The mount was already gone.
+            Some(libc::EINVAL) | Some(libc::ENOENT) => return Ok(()),
+            _ => return Err(why),
+        }
+    }
+}
+
+fn swapoff(path: &Path) -> io::Result<()> {
+    let path = cstring(path)?;
+    if unsafe { libc::swapoff(path.as_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|why| io::Error::new(io::ErrorKind::InvalidInput, why))
+}
@@ -0,0 +1,90 @@
+//! Avoids writing the chunks of an image that only contain zeroes.
+
+use libc;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use super::disk::sysfs_path;
+
+const BLKDISCARD: libc::c_ulong = 0x1277;
+const BLKZEROOUT: libc::c_ulong = 0x127F;
+
+/// How the chunks of an image which only contain zeroes are dealt with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Zeroes {
+    /// The zeroes are written as any other data would be.
+    Write,
+    /// The device is asked to zero the region itself, with `BLKZEROOUT`.
+    ZeroOut,
+    /// The image was discarded from this offset onwards before writing, so
+    /// zeroes after it are skipped, once they have been read back as zeroes.
+    Skip(u64),
+}
+
+impl Zeroes {
+    /// Chooses how zeroes will be handled on the disk. Devices which can zero
+    /// a region without being sent the data are preferred; otherwise, devices
+    /// which support discarding have the image discarded from `from` onwards,
+    /// which is where writing starts.
+    pub fn prepare(disk: &File, from: u64, len: u64) -> Zeroes {
+        if queue_limit(disk, "write_zeroes_max_bytes") > 0 {
+            Zeroes::ZeroOut
+        } else if queue_limit(disk, "discard_max_bytes") > 0
+            && from < len
+            && discard(disk, from, len - from).is_ok()
+        {
+            Zeroes::Skip(from)
+        } else {
+            Zeroes::Write
+        }
+    }
+
+    /// Deals with a region of zeroes, returning `false` if it must be written
+    /// after all.
+    pub fn handle(self, disk: &File, offset: u64, len: u64) -> bool {
+        match self {
+            Zeroes::Write => false,
+            // The kernel requires regions to be aligned to the logical block size.
+            Zeroes::ZeroOut if !offset.is_multiple_of(512) || !len.is_multiple_of(512) => false,
+            Zeroes::ZeroOut => ioctl_range(disk, BLKZEROOUT, offset, len).is_ok(),
+            Zeroes::Skip(from) => offset >= from,
+        }
+    }
+}
+
+pub(crate) fn is_zeroes(data: &[u8]) -> bool {
+    // Folding each block, rather than stopping at the first non-zero byte,
+    // allows the comparison to be vectorized.
+    data.chunks(4096)
+        .all(|block| block.iter().fold(0, |acc, &byte| acc | byte) == 0)
+}
+
+fn discard(disk: &File, offset: u64, len: u64) -> io::Result<()> {
+    ioctl_range(disk, BLKDISCARD, offset, len)
+}
+
+fn ioctl_range(disk: &File, request: libc::c_ulong, offset: u64, len: u64) -> io::Result<()> {
+    let range: [u64; 2] = [offset, len];
+    if unsafe { libc::ioctl(disk.as_raw_fd(), request as _, &range) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Reads a limit from the request queue of the block device. Partitions
+/// share the queue of their parent device.
+fn queue_limit(disk: &File, name: &str) -> u64 {
+    let device = match sysfs_path(disk) {
+        Some(device) => device,
+        None => return 0,
+    };
+
+    [device.join("queue"), device.join("../queue")]
+        .iter()
+        .filter_map(|queue| fs::read_to_string(queue.join(name)).ok())
+        .filter_map(|value| value.trim().parse::<u64>().ok())
+        .next()
+        .unwrap_or(0)
+}